@@ -0,0 +1,155 @@
+use std::io;
+
+/// Flash of the ATmega328 below Optiboot, which takes the last 512 bytes.
+pub const APP_SIZE: usize = 0x7E00;
+/// Address the firmware of the AVR keeps its flash below, RAM is mapped above it.
+const DATA_SPACE: u32 = 0x80_0000;
+
+/// Reads the firmware as it goes into the flash, from an ELF or Intel hex file.
+/// Gaps are filled with `0xFF`, like erased flash.
+pub fn load(file: &[u8]) -> io::Result<Vec<u8>> {
+    let image = if file.starts_with(b"\x7FELF") {
+        from_elf(file)
+    } else {
+        std::str::from_utf8(file)
+            .map_err(|_| invalid("neither an ELF nor an Intel hex file"))
+            .and_then(from_hex)
+    }?;
+    if image.len() > APP_SIZE {
+        return Err(invalid("the firmware does not fit below the bootloader"));
+    }
+    Ok(image)
+}
+
+/// The `PT_LOAD` segments at their physical address, which for `.data` is its copy in
+/// the flash.
+fn from_elf(file: &[u8]) -> io::Result<Vec<u8>> {
+    // 32 bit, little endian
+    if file.get(4..6) != Some(&[1, 1]) {
+        return Err(invalid("not a 32 bit little endian ELF file"));
+    }
+    let phoff = u32_at(file, 28)? as usize;
+    let phentsize = u16_at(file, 42)? as usize;
+    let phnum = u16_at(file, 44)? as usize;
+    let mut image = Vec::new();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        let kind = u32_at(file, header)?;
+        let offset = u32_at(file, header + 4)? as usize;
+        let address = u32_at(file, header + 12)?;
+        let size = u32_at(file, header + 16)? as usize;
+        if kind != 1 || size == 0 || address >= DATA_SPACE {
+            continue;
+        }
+        let data = file
+            .get(offset..offset + size)
+            .ok_or_else(|| invalid("segment beyond the end of the ELF file"))?;
+        place(&mut image, address as usize, data);
+    }
+    Ok(image)
+}
+
+fn from_hex(text: &str) -> io::Result<Vec<u8>> {
+    let mut image = Vec::new();
+    let mut base = 0;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let record = match line.strip_prefix(':').map(parse_hex) {
+            Some(Some(record)) if record.len() >= 5 => record,
+            _ => return Err(invalid("bad Intel hex record")),
+        };
+        let len = record[0] as usize;
+        if record.len() != len + 5 || record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(invalid("bad Intel hex record"));
+        }
+        let address = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let data = &record[4..4 + len];
+        match record[3] {
+            0 => place(&mut image, base + address, data),
+            1 => return Ok(image),
+            // extended segment and extended linear address
+            2 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4,
+            4 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16,
+            // start addresses
+            3 | 5 => (),
+            _ => return Err(invalid("bad Intel hex record")),
+        }
+    }
+    Err(invalid("Intel hex file without end record"))
+}
+
+fn place(image: &mut Vec<u8>, address: usize, data: &[u8]) {
+    let end = address + data.len();
+    if image.len() < end {
+        image.resize(end, 0xFF);
+    }
+    image[address..end].copy_from_slice(data);
+}
+
+fn parse_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn u16_at(file: &[u8], offset: usize) -> io::Result<u16> {
+    match file.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(invalid("truncated ELF file")),
+    }
+}
+
+fn u32_at(file: &[u8], offset: usize) -> io::Result<u32> {
+    match file.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(invalid("truncated ELF file")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_hex() {
+        let hex = ":0400000001020304F2\n:02000600AABB93\n:00000001FF\n";
+        assert_eq!(
+            load(hex.as_bytes()).unwrap(),
+            [1, 2, 3, 4, 0xFF, 0xFF, 0xAA, 0xBB]
+        );
+        assert!(load(b":0400000001020304F3\n:00000001FF\n").is_err());
+        assert!(load(b":0400000001020304F2\n").is_err());
+    }
+
+    #[test]
+    fn test_load_elf() {
+        // header and two program headers: .text at 0 and .data at 0x800100, loaded at 4
+        let mut elf = vec![0; 52 + 2 * 32];
+        elf[..6].copy_from_slice(b"\x7FELF\x01\x01");
+        elf[28..32].copy_from_slice(&52u32.to_le_bytes());
+        elf[42..44].copy_from_slice(&32u16.to_le_bytes());
+        elf[44..46].copy_from_slice(&2u16.to_le_bytes());
+        let segments = [
+            (0x0u32, 0x0u32, [1u8, 2, 3, 4]),
+            (0x80_0100, 4, [5, 6, 7, 8]),
+        ];
+        for (i, (vaddr, paddr, data)) in segments.iter().enumerate() {
+            let header = 52 + i * 32;
+            let offset = elf.len() as u32;
+            elf[header..header + 4].copy_from_slice(&1u32.to_le_bytes());
+            elf[header + 4..header + 8].copy_from_slice(&offset.to_le_bytes());
+            elf[header + 8..header + 12].copy_from_slice(&vaddr.to_le_bytes());
+            elf[header + 12..header + 16].copy_from_slice(&paddr.to_le_bytes());
+            elf[header + 16..header + 20].copy_from_slice(&4u32.to_le_bytes());
+            elf.extend_from_slice(data);
+        }
+        assert_eq!(load(&elf).unwrap(), [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time the controller gets to wake from power-down on the line break sent ahead of a
+/// command, its UART is stopped and loses that byte.
+const WAKE_DELAY: Duration = Duration::from_millis(20);
+
+/// The text protocol of a running controller, over its serial or Bluetooth link.
+///
+/// The controller prints its status every cycle, in between the answers to commands.
+/// The status starts with a line holding only `{` and ends with a line ending in `!!`.
+pub struct Link<P> {
+    port: P,
+    buf: Vec<u8>,
+}
+
+impl<P: Read + Write> Link<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            buf: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> P {
+        self.port
+    }
+
+    /// Sends `command` and returns the first line of the answer that `is_answer` accepts.
+    /// An `ERR` answer is returned as an error.
+    pub fn command(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+        is_answer: impl Fn(&str) -> bool,
+    ) -> io::Result<String> {
+        // an empty line is ignored by the controller
+        self.port.write_all(b"\n")?;
+        self.port.flush()?;
+        thread::sleep(WAKE_DELAY);
+        self.port.write_all(command.as_bytes())?;
+        self.port.write_all(b"\n")?;
+        self.port.flush()?;
+        self.answer(timeout, is_answer)
+    }
+
+    /// Waits for a line that `is_answer` accepts, skipping the status.
+    pub fn answer(
+        &mut self,
+        timeout: Duration,
+        is_answer: impl Fn(&str) -> bool,
+    ) -> io::Result<String> {
+        let deadline = Instant::now() + timeout;
+        let mut in_status = false;
+        loop {
+            let line = self.read_line(deadline)?;
+            let line = line.trim_end();
+            if in_status {
+                in_status = !line.ends_with("!!");
+            } else if line == "{" {
+                in_status = true;
+            } else if line.starts_with("ERR") {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("the controller answered {}", line),
+                ));
+            } else if is_answer(line) {
+                return Ok(line.to_string());
+            }
+        }
+    }
+
+    fn read_line(&mut self, deadline: Instant) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                return Ok(String::from_utf8_lossy(&line[..end]).into_owned());
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no answer from the controller",
+                ));
+            }
+            let mut chunk = [0; 64];
+            match self.port.read(&mut chunk) {
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Port that answers from `input` and records what was sent.
+    struct FakePort {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn link(input: &str) -> Link<FakePort> {
+        Link::new(FakePort {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_answer_skips_status() {
+        let mut link = link("{\r\n  \"distance\": \"12\",\r\n\r\n  00ff\r\n}!!\r\n0102\r\n");
+        let answer = link.command("LOG 0", Duration::from_millis(100), |line| {
+            line.bytes().all(|b| b.is_ascii_hexdigit())
+        });
+        assert_eq!(answer.unwrap(), "0102");
+        assert_eq!(link.into_inner().output, b"\nLOG 0\n");
+    }
+
+    #[test]
+    fn test_error_answer() {
+        let mut errors = link("ERR Range\n");
+        let answer = errors.command("LOG 9", Duration::from_millis(100), |_| true);
+        assert!(answer.is_err());
+        let mut silent = link("");
+        let answer = silent.answer(Duration::from_millis(10), |_| true);
+        assert_eq!(answer.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+
+/// A record is the sequence number, the kind of event, two arguments
+/// and the packed RTC time.
+pub const RECORD_LEN: usize = 8;
+/// Records the controller prints for one `LOG <page>`.
+pub const PAGE_RECORDS: usize = 8;
+/// Pages the log of 64 records fills.
+pub const PAGES: u8 = 8;
+
+const ZONE_NAMES: [&str; 3] = ["filter", "pump_pit", "inlet"];
+const VALVE_NAMES: [&str; 5] = ["einlass", "abwasser", "filterwasser", "bridge", "pump"];
+
+/// One record of the event log, as written by `EventLog::push` of the firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    pub seq: u8,
+    pub kind: u8,
+    pub args: [u8; 2],
+    pub time: Time,
+}
+
+impl Record {
+    /// Decodes a record, `None` unless `bytes` holds exactly one.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_LEN {
+            return None;
+        }
+        let time = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        Some(Self {
+            seq: bytes[0],
+            kind: bytes[1],
+            args: [bytes[2], bytes[3]],
+            time: Time::unpack(time),
+        })
+    }
+
+    fn arg16(&self) -> u16 {
+        u16::from_le_bytes(self.args)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} #{:<3} ", self.time, self.seq)?;
+        let [a, b] = self.args;
+        match self.kind {
+            1 => write!(
+                f,
+                "boot, reset by {}, {} resets since power-on",
+                ResetFlags(a),
+                b
+            ),
+            2 => write!(f, "mode {}", mode_name(a)),
+            3 => write!(f, "job start {}", Job(a, b)),
+            4 => write!(f, "job stop {}", Job(a, b)),
+            5 => write!(f, "breach in {}, action {}", Zones(a), action_name(b)),
+            6 => write!(f, "breach cleared"),
+            7 => write!(f, "stuck valves {}", Valves(a)),
+            8 => write!(f, "config changed"),
+            9 => write!(f, "interlock rule {} rejected {}", Valves(b), Valves(a)),
+            10 => write!(f, "job {} {}", Job(b, 0xFF), outcome_name(a)),
+            11 => write!(f, "panic at line {}", self.arg16()),
+            12 => match a {
+                0 => write!(f, "reboot"),
+                1 => write!(f, "reboot into the bootloader"),
+                2 => write!(f, "reboot, the RTC could not be read"),
+                _ => write!(f, "reboot {}", a),
+            },
+            13 => write!(f, "stack low, {} bytes left", self.arg16()),
+            kind => write!(f, "event {} {} {}", kind, a, b),
+        }
+    }
+}
+
+/// Time of a record, packed into 32 bits: years since 2000, month, day, hour, minute and
+/// second with 6, 4, 5, 5, 6 and 6 bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Time {
+    fn unpack(time: u32) -> Self {
+        Self {
+            year: 2000 + (time >> 26) as u16,
+            month: (time >> 22 & 0xF) as u8,
+            day: (time >> 17 & 0x1F) as u8,
+            hour: (time >> 12 & 0x1F) as u8,
+            minute: (time >> 6 & 0x3F) as u8,
+            second: (time & 0x3F) as u8,
+        }
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Reads a line of hex digits as printed by `LOG`, `None` if it is anything else.
+pub fn parse_hex(line: &str) -> Option<Vec<u8>> {
+    let digits = line.trim().as_bytes();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// Decodes all records of `data`, which holds whole records one after the other.
+pub fn decode_all(data: &[u8]) -> Vec<Record> {
+    data.chunks(RECORD_LEN).filter_map(Record::decode).collect()
+}
+
+struct ResetFlags(u8);
+
+impl fmt::Display for ResetFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = ["power-on", "external", "brown-out", "watchdog"];
+        write_bits(f, self.0, &names, "nothing")
+    }
+}
+
+struct Zones(u8);
+
+impl fmt::Display for Zones {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_bits(f, self.0, &ZONE_NAMES, "no zone")
+    }
+}
+
+struct Valves(u8);
+
+impl fmt::Display for Valves {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_bits(f, self.0, &VALVE_NAMES, "none")
+    }
+}
+
+/// Job number and recipe, a recipe of `0xFF` is not known.
+struct Job(u8, u8);
+
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.0, self.1) {
+            (0, _) => write!(f, "idle"),
+            (1, _) => write!(f, "filter"),
+            (2, 0) => write!(f, "clean (nightly)"),
+            (2, 1) => write!(f, "clean (rinse)"),
+            (2, _) => write!(f, "clean"),
+            (job, _) => write!(f, "{}", job),
+        }
+    }
+}
+
+fn write_bits(f: &mut fmt::Formatter, bits: u8, names: &[&str], none: &str) -> fmt::Result {
+    if bits == 0 {
+        return write!(f, "{}", none);
+    }
+    let mut first = true;
+    for bit in 0..8 {
+        if bits & 1 << bit == 0 {
+            continue;
+        }
+        if !first {
+            write!(f, "+")?;
+        }
+        match names.get(bit) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "bit{}", bit)?,
+        }
+        first = false;
+    }
+    Ok(())
+}
+
+fn mode_name(mode: u8) -> &'static str {
+    match mode {
+        0 => "automatic",
+        1 => "manual",
+        2 => "breach",
+        3 => "fault",
+        4 => "off",
+        _ => "unknown",
+    }
+}
+
+fn action_name(action: u8) -> &'static str {
+    match action {
+        0 => "alarm",
+        1 => "close_inlet",
+        2 => "idle",
+        _ => "unknown",
+    }
+}
+
+fn outcome_name(outcome: u8) -> &'static str {
+    match outcome {
+        0 => "resumed",
+        1 => "restarted",
+        2 => "abandoned",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_records() {
+        // 2021-06-01 12:34:56, packed as the firmware does it
+        let time: u32 = 21 << 26 | 6 << 22 | 1 << 17 | 12 << 12 | 34 << 6 | 56;
+        let t = time.to_le_bytes();
+        let data = parse_hex(&format!(
+            "07010902{:02x}{:02x}{:02x}{:02x}08090b04{:02x}{:02x}{:02x}{:02x}",
+            t[0], t[1], t[2], t[3], t[0], t[1], t[2], t[3]
+        ))
+        .unwrap();
+        let records = decode_all(&data);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].seq, 7);
+        assert_eq!(
+            records[0].to_string(),
+            "2021-06-01 12:34:56 #7   boot, reset by power-on+watchdog, 2 resets since power-on"
+        );
+        assert_eq!(
+            records[1].to_string(),
+            "2021-06-01 12:34:56 #8   interlock rule filterwasser rejected einlass+abwasser+bridge"
+        );
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex(""), Some(vec![]));
+        assert_eq!(parse_hex("0aFf\r"), Some(vec![0x0A, 0xFF]));
+        assert_eq!(parse_hex("OK"), None);
+        assert_eq!(parse_hex("abc"), None);
+    }
+}
@@ -0,0 +1,187 @@
+//! Host tool for the controller, `fkctl log <port>` downloads and decodes the event log,
+//! `fkctl flash <port> <firmware>` updates the firmware over the serial or Bluetooth link.
+
+mod image;
+mod link;
+mod log;
+mod serial;
+mod stk500;
+
+use link::Link;
+use std::fs;
+use std::io::{self, BufRead};
+use std::process;
+use std::time::{Duration, Instant};
+use stk500::Stk500;
+
+/// Baud rate of the firmware.
+const BAUD: u32 = 9600;
+/// The controller answers in between two cycles, which take about a second.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+/// The controller moves its valves to idle for up to 5 s before it answers `BOOTLOADER`.
+const BOOTLOADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Baud rate of Optiboot on the Nano.
+const BOOTLOADER_BAUD: u32 = 115_200;
+/// Optiboot waits about a second for the host after the reset that follows the `OK`.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+/// The new firmware sets up the clock and measures once before it answers.
+const START_TIMEOUT: Duration = Duration::from_secs(15);
+
+const USAGE: &str = "usage: fkctl log <port>    download and decode the event log
+       fkctl log -         decode the output of LOG read from stdin
+       fkctl flash <port> <firmware.elf|firmware.hex> [<bootloader baud>]
+                           upload new firmware and check it is this build";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["log", "-"] => decode_stdin(),
+        ["log", port] => download_log(port),
+        ["flash", port, firmware] => flash(port, firmware, BOOTLOADER_BAUD),
+        ["flash", port, firmware, baud] => match baud.parse() {
+            Ok(baud) => flash(port, firmware, baud),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad baud rate {}", baud),
+            )),
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("fkctl: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Reads all pages from the controller and prints the records oldest first.
+fn download_log(port: &str) -> io::Result<()> {
+    let mut link = Link::new(serial::open(port, BAUD)?);
+    let mut data = Vec::new();
+    for page in 0..log::PAGES {
+        let command = format!("LOG {}", page);
+        let answer = link.command(&command, ANSWER_TIMEOUT, |line| {
+            log::parse_hex(line).is_some()
+        })?;
+        let records = log::parse_hex(&answer).unwrap_or_default();
+        data.extend_from_slice(&records);
+        if records.len() < log::PAGE_RECORDS * log::RECORD_LEN {
+            break;
+        }
+    }
+    print_records(&data);
+    Ok(())
+}
+
+/// Decodes lines of hex as `LOG` prints them, other lines are skipped.
+fn decode_stdin() -> io::Result<()> {
+    let mut data = Vec::new();
+    for line in io::stdin().lock().lines() {
+        if let Some(records) = log::parse_hex(&line?) {
+            data.extend_from_slice(&records);
+        }
+    }
+    print_records(&data);
+    Ok(())
+}
+
+/// The pages hold the newest record first.
+fn print_records(data: &[u8]) {
+    for record in log::decode_all(data).iter().rev() {
+        println!("{}", record);
+    }
+}
+
+/// Has the controller start its bootloader, uploads and verifies `firmware` and checks
+/// that the controller comes back with the build of the image.
+///
+/// If the bootloader cannot be reached, it times out and starts the old firmware again.
+/// An upload that breaks off halfway leaves no working firmware, which then has to be
+/// uploaded over USB.
+fn flash(port: &str, firmware: &str, bootloader_baud: u32) -> io::Result<()> {
+    let image = image::load(&fs::read(firmware)?)?;
+    let mut link = Link::new(serial::open(port, BAUD)?);
+    link.command("BOOTLOADER", BOOTLOADER_TIMEOUT, |line| line == "OK")?;
+    drop(link);
+
+    // the controller resets right after its OK
+    let mut bootloader = Stk500::new(serial::open(port, bootloader_baud)?);
+    bootloader.sync(SYNC_TIMEOUT)?;
+    let signature = bootloader.signature()?;
+    if signature != stk500::SIGNATURE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("not an ATmega328P, signature {:02x?}", signature),
+        ));
+    }
+    bootloader.enter_programming()?;
+    eprintln!("uploading {} bytes", image.len());
+    stk500::upload(&mut bootloader, &image)?;
+    bootloader.leave_programming()?;
+    drop(bootloader);
+    eprintln!("verified, waiting for the new firmware");
+
+    let mut link = Link::new(serial::open(port, BAUD)?);
+    let info = wait_for_info(&mut link)?;
+    println!("{}", info);
+    match info_git(&info) {
+        Some(git) if contains(&image, git.as_bytes()) => Ok(()),
+        git => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the controller runs build {}, which is not in {}",
+                git.unwrap_or("unknown"),
+                firmware
+            ),
+        )),
+    }
+}
+
+/// Asks for `INFO` until the firmware has started and answers.
+fn wait_for_info<P: io::Read + io::Write>(link: &mut Link<P>) -> io::Result<String> {
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        match link.command("INFO", ANSWER_TIMEOUT, |line| {
+            line.starts_with("{\"version\"")
+        }) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && Instant::now() < deadline => (),
+            result => return result,
+        }
+    }
+}
+
+/// Whether `image` holds `text`, the firmware keeps the git hash it reports in its flash.
+fn contains(image: &[u8], text: &[u8]) -> bool {
+    !text.is_empty() && image.windows(text.len()).any(|window| window == text)
+}
+
+/// The git hash in the answer to `INFO`.
+fn info_git(info: &str) -> Option<&str> {
+    const KEY: &str = "\"git\": \"";
+    let start = info.find(KEY)? + KEY.len();
+    let len = info[start..].find('"')?;
+    Some(&info[start..start + len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_info_git() {
+        let info = r#"{"version": "0.1.0", "git": "39c4e3d", "features": []}"#;
+        assert_eq!(info_git(info), Some("39c4e3d"));
+        assert_eq!(info_git("{}"), None);
+    }
+
+    #[test]
+    fn test_contains() {
+        let image = b"\x0c\x94\x5c\x0039c4e3d\x00";
+        assert!(contains(image, b"39c4e3d"));
+        assert!(!contains(image, b"0efc3c2"));
+        assert!(!contains(image, b""));
+    }
+}
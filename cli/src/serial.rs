@@ -0,0 +1,27 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::process::Command;
+
+/// Opens the serial port at `path` raw with `baud`, reads return after 100 ms without data.
+pub fn open(path: &str, baud: u32) -> io::Result<File> {
+    let status = Command::new("stty")
+        .args(&[
+            "-F",
+            path,
+            &baud.to_string(),
+            "raw",
+            "-echo",
+            "min",
+            "0",
+            "time",
+            "1",
+        ])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("stty could not set up {}", path),
+        ));
+    }
+    OpenOptions::new().read(true).write(true).open(path)
+}
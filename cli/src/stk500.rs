@@ -0,0 +1,310 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Bytes Optiboot writes to the flash of the ATmega328 at a time.
+pub const PAGE_SIZE: usize = 128;
+/// Signature of the ATmega328P.
+pub const SIGNATURE: [u8; 3] = [0x1E, 0x95, 0x0F];
+
+const STK_OK: u8 = 0x10;
+const STK_INSYNC: u8 = 0x14;
+const CRC_EOP: u8 = 0x20;
+const STK_GET_SYNC: u8 = 0x30;
+const STK_ENTER_PROGMODE: u8 = 0x50;
+const STK_LEAVE_PROGMODE: u8 = 0x51;
+const STK_LOAD_ADDRESS: u8 = 0x55;
+const STK_PROG_PAGE: u8 = 0x64;
+const STK_READ_PAGE: u8 = 0x74;
+const STK_READ_SIGN: u8 = 0x75;
+/// Memory type of the page commands.
+const FLASH: u8 = b'F';
+
+/// How long Optiboot takes at most to answer a command, including writing a page.
+const ANSWER_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time for one attempt to get in sync.
+const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The subset of STK500 version 1 that Optiboot speaks.
+pub struct Stk500<P> {
+    port: P,
+}
+
+impl<P: Read + Write> Stk500<P> {
+    pub fn new(port: P) -> Self {
+        Self { port }
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> P {
+        self.port
+    }
+
+    /// Keeps asking for sync until the bootloader answers or `timeout` is over.
+    ///
+    /// The controller resets into the bootloader right after its `OK`, and Optiboot
+    /// only waits about a second for the host, so this starts at once and tries often.
+    pub fn sync(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.drain()?;
+            match self.command(&[STK_GET_SYNC], 0, SYNC_TIMEOUT) {
+                Ok(_) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => (),
+            }
+        }
+    }
+
+    pub fn signature(&mut self) -> io::Result<[u8; 3]> {
+        let answer = self.command(&[STK_READ_SIGN], 3, ANSWER_TIMEOUT)?;
+        Ok([answer[0], answer[1], answer[2]])
+    }
+
+    pub fn enter_programming(&mut self) -> io::Result<()> {
+        self.command(&[STK_ENTER_PROGMODE], 0, ANSWER_TIMEOUT)
+            .map(drop)
+    }
+
+    /// Optiboot starts the firmware right after this.
+    pub fn leave_programming(&mut self) -> io::Result<()> {
+        self.command(&[STK_LEAVE_PROGMODE], 0, ANSWER_TIMEOUT)
+            .map(drop)
+    }
+
+    /// Writes `data` to the flash page at `address`.
+    pub fn write_page(&mut self, address: usize, data: &[u8]) -> io::Result<()> {
+        self.load_address(address)?;
+        let len = (data.len() as u16).to_be_bytes();
+        let mut command = vec![STK_PROG_PAGE, len[0], len[1], FLASH];
+        command.extend_from_slice(data);
+        self.command(&command, 0, ANSWER_TIMEOUT).map(drop)
+    }
+
+    /// Reads `len` bytes of the flash from `address`.
+    pub fn read_page(&mut self, address: usize, len: usize) -> io::Result<Vec<u8>> {
+        self.load_address(address)?;
+        let bytes = (len as u16).to_be_bytes();
+        self.command(
+            &[STK_READ_PAGE, bytes[0], bytes[1], FLASH],
+            len,
+            ANSWER_TIMEOUT,
+        )
+    }
+
+    /// Addresses of the flash are in words.
+    fn load_address(&mut self, address: usize) -> io::Result<()> {
+        let word = ((address / 2) as u16).to_le_bytes();
+        self.command(&[STK_LOAD_ADDRESS, word[0], word[1]], 0, ANSWER_TIMEOUT)
+            .map(drop)
+    }
+
+    /// Sends `command` and returns the `len` bytes of the answer between `STK_INSYNC` and
+    /// `STK_OK`.
+    fn command(&mut self, command: &[u8], len: usize, timeout: Duration) -> io::Result<Vec<u8>> {
+        self.port.write_all(command)?;
+        self.port.write_all(&[CRC_EOP])?;
+        self.port.flush()?;
+        let mut answer = vec![0; len + 2];
+        self.read_exact(&mut answer, Instant::now() + timeout)?;
+        if answer[0] != STK_INSYNC || answer[len + 1] != STK_OK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the bootloader is out of sync",
+            ));
+        }
+        answer.pop();
+        answer.remove(0);
+        Ok(answer)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no answer from the bootloader",
+                ));
+            }
+            match self.port.read(&mut buf[done..]) {
+                Ok(len) => done += len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops what is left of the status of the firmware and of earlier attempts.
+    fn drain(&mut self) -> io::Result<()> {
+        let mut buf = [0; 64];
+        while self.port.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+}
+
+/// Writes `image` page by page from address 0, then reads it all back.
+pub fn upload<P: Read + Write>(bootloader: &mut Stk500<P>, image: &[u8]) -> io::Result<()> {
+    for (page, data) in image.chunks(PAGE_SIZE).enumerate() {
+        bootloader.write_page(page * PAGE_SIZE, data)?;
+    }
+    for (page, data) in image.chunks(PAGE_SIZE).enumerate() {
+        if bootloader.read_page(page * PAGE_SIZE, data.len())? != data {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("verify failed at 0x{:04x}", page * PAGE_SIZE),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::APP_SIZE;
+    use std::collections::VecDeque;
+
+    /// Answers like Optiboot, from a flash of its own.
+    struct FakeOptiboot {
+        flash: Vec<u8>,
+        address: usize,
+        received: Vec<u8>,
+        answers: VecDeque<u8>,
+        /// Byte flipped in the flash when its page is written, to fail the verify.
+        corrupt: Option<usize>,
+        /// Attempts to sync that go unanswered, as if the reset had not happened yet.
+        ignore_syncs: usize,
+    }
+
+    impl FakeOptiboot {
+        fn new() -> Self {
+            Self {
+                flash: vec![0xFF; APP_SIZE],
+                address: 0,
+                received: Vec::new(),
+                answers: VecDeque::new(),
+                corrupt: None,
+                ignore_syncs: 0,
+            }
+        }
+
+        /// Length of the command at the start of `received`, with its `CRC_EOP`.
+        fn command_len(&self) -> Option<usize> {
+            let len = match *self.received.first()? {
+                STK_LOAD_ADDRESS => 4,
+                STK_PROG_PAGE => {
+                    let len = self.received.get(1..3)?;
+                    5 + u16::from_be_bytes([len[0], len[1]]) as usize
+                }
+                STK_READ_PAGE => 5,
+                _ => 2,
+            };
+            Some(len).filter(|len| self.received.len() >= *len)
+        }
+
+        fn execute(&mut self, command: &[u8]) {
+            if command.last() != Some(&CRC_EOP) {
+                return;
+            }
+            let mut answer = vec![];
+            match command[0] {
+                STK_GET_SYNC if self.ignore_syncs > 0 => {
+                    self.ignore_syncs -= 1;
+                    return;
+                }
+                STK_LOAD_ADDRESS => {
+                    self.address = u16::from_le_bytes([command[1], command[2]]) as usize * 2;
+                }
+                STK_PROG_PAGE => {
+                    let data = &command[4..command.len() - 1];
+                    self.flash[self.address..][..data.len()].copy_from_slice(data);
+                    match self.corrupt {
+                        Some(corrupt)
+                            if (self.address..self.address + data.len()).contains(&corrupt) =>
+                        {
+                            self.flash[corrupt] ^= 1
+                        }
+                        _ => (),
+                    }
+                }
+                STK_READ_PAGE => {
+                    let len = u16::from_be_bytes([command[1], command[2]]) as usize;
+                    answer.extend_from_slice(&self.flash[self.address..][..len]);
+                }
+                STK_READ_SIGN => answer.extend_from_slice(&SIGNATURE),
+                _ => (),
+            }
+            self.answers.push_back(STK_INSYNC);
+            self.answers.extend(answer);
+            self.answers.push_back(STK_OK);
+        }
+    }
+
+    impl Read for FakeOptiboot {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut len = 0;
+            while len < buf.len() {
+                match self.answers.pop_front() {
+                    Some(b) => buf[len] = b,
+                    None => break,
+                }
+                len += 1;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeOptiboot {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.extend_from_slice(buf);
+            while let Some(len) = self.command_len() {
+                let command: Vec<u8> = self.received.drain(..len).collect();
+                self.execute(&command);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn image() -> Vec<u8> {
+        (0..300).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn test_upload() {
+        let mut fake = FakeOptiboot::new();
+        fake.ignore_syncs = 3;
+        let mut bootloader = Stk500::new(fake);
+        bootloader.sync(Duration::from_secs(1)).unwrap();
+        assert_eq!(bootloader.signature().unwrap(), SIGNATURE);
+        bootloader.enter_programming().unwrap();
+        upload(&mut bootloader, &image()).unwrap();
+        bootloader.leave_programming().unwrap();
+        let fake = bootloader.into_inner();
+        assert_eq!(fake.flash[..300], image()[..]);
+        assert!(fake.flash[300..].iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn test_verify_fails() {
+        let mut fake = FakeOptiboot::new();
+        fake.corrupt = Some(200);
+        let mut bootloader = Stk500::new(fake);
+        bootloader.sync(Duration::from_secs(1)).unwrap();
+        let e = upload(&mut bootloader, &image()).unwrap_err();
+        assert_eq!(e.to_string(), "verify failed at 0x0080");
+    }
+
+    #[test]
+    fn test_no_bootloader() {
+        let mut fake = FakeOptiboot::new();
+        fake.ignore_syncs = usize::MAX;
+        let mut bootloader = Stk500::new(fake);
+        let e = bootloader.sync(Duration::from_millis(250)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
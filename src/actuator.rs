@@ -0,0 +1,288 @@
+#![allow(dead_code)]
+
+use arduino_hal::port::mode::{Input, Io, Output, PullUp};
+use arduino_hal::port::Pin;
+use ufmt::{uDebug, uWrite};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ActuatorState {
+    Closed,
+    Opening,
+    Open,
+    Closing,
+}
+
+impl ActuatorState {
+    /// Whether the actuator was last told to open, even if it has not arrived yet.
+    pub fn is_commanded_open(&self) -> bool {
+        matches!(self, ActuatorState::Opening | ActuatorState::Open)
+    }
+
+    pub fn is_moving(&self) -> bool {
+        matches!(self, ActuatorState::Opening | ActuatorState::Closing)
+    }
+}
+
+impl uDebug for ActuatorState {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        let name = match self {
+            ActuatorState::Closed => progmem!(b"closed"),
+            ActuatorState::Opening => progmem!(b"opening"),
+            ActuatorState::Open => progmem!(b"open"),
+            ActuatorState::Closing => progmem!(b"closing"),
+        };
+        uwrite!(f, "{}", name)
+    }
+}
+
+/// Real position of a valve as reported by its feedback input.
+pub struct Position(pub Option<bool>);
+
+impl uDebug for Position {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        let name = match self.0 {
+            Some(true) => progmem!(b"open"),
+            Some(false) => progmem!(b"closed"),
+            None => progmem!(b"unknown"),
+        };
+        uwrite!(f, "{}", name)
+    }
+}
+
+/// Something that opens and closes a water line.
+pub trait Actuator {
+    /// Starts moving towards open or closed, `now` in milliseconds.
+    fn set(&mut self, open: bool, now: u32);
+    /// Advances a running movement, `now` in milliseconds.
+    fn poll(&mut self, now: u32);
+    fn state(&self) -> ActuatorState;
+    /// Whether the valve really is open, `None` without a feedback input.
+    fn actual(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Input that reports the real position of a valve.
+pub trait Feedback {
+    fn is_open(&self) -> bool;
+}
+
+/// A limit switch, or a current sensor with a digital output, that pulls its
+/// input low while the valve is open.
+pub struct LimitSwitch<P> {
+    pin: Pin<Input<PullUp>, P>,
+}
+
+impl<P> LimitSwitch<P>
+where
+    P: avr_hal_generic::port::PinOps,
+{
+    pub fn new(pin: Pin<Input<PullUp>, P>) -> Self {
+        Self { pin }
+    }
+}
+
+impl<P> Feedback for LimitSwitch<P>
+where
+    P: avr_hal_generic::port::PinOps,
+{
+    fn is_open(&self) -> bool {
+        self.pin.is_low()
+    }
+}
+
+/// An actuator with a feedback input for its real position.
+pub struct Sensed<A, F> {
+    actuator: A,
+    feedback: F,
+}
+
+impl<A: Actuator, F: Feedback> Sensed<A, F> {
+    pub fn new(actuator: A, feedback: F) -> Self {
+        Self { actuator, feedback }
+    }
+}
+
+impl<A: Actuator, F: Feedback> Actuator for Sensed<A, F> {
+    fn set(&mut self, open: bool, now: u32) {
+        self.actuator.set(open, now);
+    }
+
+    fn poll(&mut self, now: u32) {
+        self.actuator.poll(now);
+    }
+
+    fn state(&self) -> ActuatorState {
+        self.actuator.state()
+    }
+
+    fn actual(&self) -> Option<bool> {
+        Some(self.feedback.is_open())
+    }
+}
+
+/// A solenoid valve switched by a single relay.
+///
+/// Many relay boards are active-low, they energize the relay while the pin is low.
+pub struct Solenoid<P> {
+    pin: Pin<Output, P>,
+    active_low: bool,
+    open: bool,
+}
+
+impl<P> Solenoid<P>
+where
+    P: avr_hal_generic::port::PinOps,
+{
+    /// Takes over `pin` and keeps the valve closed, the pin never drives the relay on
+    /// on its way to becoming an output.
+    pub fn new<M: Io>(pin: Pin<M, P>, active_low: bool) -> Self {
+        Self {
+            pin: relay_output(pin, active_low),
+            active_low,
+            open: false,
+        }
+    }
+
+    fn drive(&mut self, open: bool) {
+        drive_relay(&mut self.pin, open, self.active_low);
+        self.open = open;
+    }
+}
+
+impl<P> Actuator for Solenoid<P>
+where
+    P: avr_hal_generic::port::PinOps,
+{
+    fn set(&mut self, open: bool, _now: u32) {
+        self.drive(open);
+    }
+
+    fn poll(&mut self, _now: u32) {}
+
+    fn state(&self) -> ActuatorState {
+        if self.open {
+            ActuatorState::Open
+        } else {
+            ActuatorState::Closed
+        }
+    }
+}
+
+/// Stands in for the pump on installs without one.
+pub struct NoPump;
+
+impl Actuator for NoPump {
+    fn set(&mut self, _open: bool, _now: u32) {}
+
+    fn poll(&mut self, _now: u32) {}
+
+    fn state(&self) -> ActuatorState {
+        ActuatorState::Closed
+    }
+}
+
+/// A motorized ball valve with separate lines to drive it open and closed.
+///
+/// The motor is driven for `travel_ms` and switched off once the valve has
+/// reached its end position. The lines switch relays like a `Solenoid`.
+pub struct MotorValve<PO, PC> {
+    open_pin: Pin<Output, PO>,
+    close_pin: Pin<Output, PC>,
+    active_low: bool,
+    travel_ms: u32,
+    state: ActuatorState,
+    started: u32,
+}
+
+impl<PO, PC> MotorValve<PO, PC>
+where
+    PO: avr_hal_generic::port::PinOps,
+    PC: avr_hal_generic::port::PinOps,
+{
+    /// Takes over the motor lines and starts driving the valve closed.
+    pub fn new<MO: Io, MC: Io>(
+        open_pin: Pin<MO, PO>,
+        close_pin: Pin<MC, PC>,
+        travel_ms: u32,
+        active_low: bool,
+    ) -> Self {
+        let mut valve = Self {
+            open_pin: relay_output(open_pin, active_low),
+            close_pin: relay_output(close_pin, active_low),
+            active_low,
+            travel_ms,
+            state: ActuatorState::Open,
+            started: 0,
+        };
+        valve.set(false, 0);
+        valve
+    }
+}
+
+impl<PO, PC> Actuator for MotorValve<PO, PC>
+where
+    PO: avr_hal_generic::port::PinOps,
+    PC: avr_hal_generic::port::PinOps,
+{
+    fn set(&mut self, open: bool, now: u32) {
+        if self.state.is_commanded_open() == open {
+            return;
+        }
+        // never drive both lines at once
+        if open {
+            drive_relay(&mut self.close_pin, false, self.active_low);
+            drive_relay(&mut self.open_pin, true, self.active_low);
+            self.state = ActuatorState::Opening;
+        } else {
+            drive_relay(&mut self.open_pin, false, self.active_low);
+            drive_relay(&mut self.close_pin, true, self.active_low);
+            self.state = ActuatorState::Closing;
+        }
+        self.started = now;
+    }
+
+    fn poll(&mut self, now: u32) {
+        if self.state.is_moving() && now.wrapping_sub(self.started) >= self.travel_ms {
+            drive_relay(&mut self.open_pin, false, self.active_low);
+            drive_relay(&mut self.close_pin, false, self.active_low);
+            self.state = match self.state {
+                ActuatorState::Opening => ActuatorState::Open,
+                _ => ActuatorState::Closed,
+            };
+        }
+    }
+
+    fn state(&self) -> ActuatorState {
+        self.state
+    }
+}
+
+/// Makes `pin` an output at the level that keeps its relay off.
+fn relay_output<M: Io, P>(pin: Pin<M, P>, active_low: bool) -> Pin<Output, P>
+where
+    P: avr_hal_generic::port::PinOps,
+{
+    if active_low {
+        pin.into_output_high()
+    } else {
+        pin.into_output()
+    }
+}
+
+fn drive_relay<P>(pin: &mut Pin<Output, P>, on: bool, active_low: bool)
+where
+    P: avr_hal_generic::port::PinOps,
+{
+    if on != active_low {
+        pin.set_high();
+    } else {
+        pin.set_low();
+    }
+}
@@ -0,0 +1,504 @@
+#![allow(dead_code)]
+
+use crate::checksum::crc16;
+use crate::control::ValveMask;
+use crate::leak::{self, ZONE_COUNT};
+use crate::recipe::{self, Recipe, Step, MAX_STEPS, RECIPE_COUNT};
+use crate::time::Duration;
+use ufmt::{uDebug, uWrite, uwrite};
+
+/// Version of the layout written by `to_bytes`, a config of another version is not read.
+pub const CONFIG_VERSION: u8 = 1;
+
+/// Positions in the serialized config: valve table, recipes, interlocks, settle times,
+/// stuck timeout, pump delays and the thresholds of the leak zones, followed by their
+/// actions packed into one byte. The valve table keeps a byte for the clean job, which
+/// is unused.
+const RECIPE_LEN: usize = 1 + MAX_STEPS * 3;
+const RECIPES: usize = JOB_COUNT;
+const INTERLOCKS: usize = RECIPES + RECIPE_COUNT * RECIPE_LEN;
+const SETTLE: usize = INTERLOCKS + MAX_INTERLOCKS;
+const STUCK: usize = SETTLE + VALVE_COUNT;
+const PUMP: usize = STUCK + 1;
+const LEAK: usize = PUMP + 2;
+/// Size of the serialized config.
+pub const CONFIG_LEN: usize = LEAK + ZONE_COUNT * 2 + 1;
+
+pub const JOB_IDLE: u8 = 0;
+pub const JOB_FILTER: u8 = 1;
+pub const JOB_CLEAN: u8 = 2;
+pub const JOB_COUNT: usize = 3;
+
+pub const MAX_INTERLOCKS: usize = 4;
+
+pub const VALVE_COUNT: usize = 4;
+
+pub enum Error {
+    Job,
+    Pattern,
+    Interlock,
+    /// The valve pattern is forbidden by the interlock rule.
+    Forbidden(u8),
+    Valve,
+    /// A stuck timeout of zero would mark a valve stuck on its first disagreeing reading.
+    Timeout,
+    Threshold,
+    Action,
+    Step(recipe::Error),
+}
+
+/// Valve pattern for every job, to match the plumbing of a site.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ValveTable([u8; JOB_COUNT]);
+
+impl ValveTable {
+    pub fn pattern(&self, job: u8) -> u8 {
+        self.0.get(job as usize).copied().unwrap_or(ValveMask::IDLE)
+    }
+
+    /// Sets the pattern of `job` if it passes the validation rules of `is_valid`.
+    /// The clean job takes its valves from the steps of its recipe and has no pattern here.
+    pub fn set_pattern(&mut self, job: u8, pattern: u8) -> Result<(), Error> {
+        if job >= JOB_CLEAN {
+            return Err(Error::Job);
+        }
+        if !Self::is_valid(job, pattern) {
+            return Err(Error::Pattern);
+        }
+        self.0[job as usize] = pattern;
+        Ok(())
+    }
+
+    /// Whether `pattern` passes the validation rule of `job`:
+    /// - idle keeps the einlass closed
+    /// - filter opens einlass and filterwasser
+    /// - clean opens the abwasser and keeps the filterwasser closed, for every recipe step
+    pub fn is_valid(job: u8, pattern: u8) -> bool {
+        let open = |mask: u8| pattern & mask == mask;
+        let closed = |mask: u8| pattern & mask == 0;
+        if !ValveMask::is_valid(pattern) {
+            return false;
+        }
+        match job {
+            JOB_IDLE => closed(ValveMask::EINLASS),
+            JOB_FILTER => open(ValveMask::EINLASS | ValveMask::FILTERWASSER),
+            JOB_CLEAN => open(ValveMask::ABWASSER) && closed(ValveMask::FILTERWASSER),
+            _ => false,
+        }
+    }
+}
+
+impl Default for ValveTable {
+    fn default() -> Self {
+        Self([ValveMask::IDLE, ValveMask::FILTER, ValveMask::CLEAN])
+    }
+}
+
+impl uDebug for ValveTable {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(
+            f,
+            r#"{{"idle": {}, "filter": {}}}"#,
+            self.0[JOB_IDLE as usize],
+            self.0[JOB_FILTER as usize]
+        )
+    }
+}
+
+/// Sets of valves that must never be open at the same time.
+///
+/// Every rule is a valve pattern, a rule of 0 is unused.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Interlocks([u8; MAX_INTERLOCKS]);
+
+impl Interlocks {
+    /// Returns the first rule that forbids `pattern`.
+    pub fn check(&self, pattern: u8) -> Result<(), u8> {
+        match self
+            .0
+            .iter()
+            .find(|&&rule| rule != 0 && pattern & rule == rule)
+        {
+            Some(rule) => Err(*rule),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the rule at `index`, a rule has to name at least two valves.
+    pub fn set_rule(&mut self, index: u8, rule: u8) -> Result<(), Error> {
+        if index as usize >= MAX_INTERLOCKS {
+            return Err(Error::Interlock);
+        }
+        if rule & !ValveMask::ALL != 0 || (rule != 0 && rule.count_ones() < 2) {
+            return Err(Error::Pattern);
+        }
+        self.0[index as usize] = rule;
+        Ok(())
+    }
+}
+
+impl Default for Interlocks {
+    fn default() -> Self {
+        // dirty water must never pass into the clean line
+        Self([ValveMask::FILTERWASSER | ValveMask::BRIDGE, 0, 0, 0])
+    }
+}
+
+impl uDebug for Interlocks {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, "{:?}", self.0)
+    }
+}
+
+/// Time in tenths of a second every valve gets to settle after switching,
+/// before the next valve of a transition is switched.
+#[derive(Clone, Copy, PartialEq)]
+pub struct SettleTimes([u8; VALVE_COUNT]);
+
+impl SettleTimes {
+    /// Settle time of the valve with the bit `valve` in a valve pattern, in milliseconds.
+    pub fn millis(&self, valve: u8) -> u32 {
+        let index = valve.trailing_zeros() as usize;
+        self.0.get(index).map_or(0, |t| *t as u32 * 100)
+    }
+
+    pub fn set(&mut self, index: u8, tenths: u8) -> Result<(), Error> {
+        *self.0.get_mut(index as usize).ok_or(Error::Valve)? = tenths;
+        Ok(())
+    }
+}
+
+impl Default for SettleTimes {
+    fn default() -> Self {
+        Self([5; VALVE_COUNT])
+    }
+}
+
+impl uDebug for SettleTimes {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, "{:?}", self.0)
+    }
+}
+
+/// Delays of the pump in tenths of a second.
+#[derive(Clone, Copy, PartialEq)]
+pub struct PumpDelays {
+    /// Time the valves have to be in place before the pump starts.
+    pub on: u8,
+    /// Time the pump gets to run down before the valves move.
+    pub off: u8,
+}
+
+impl PumpDelays {
+    pub fn on_millis(&self) -> u32 {
+        self.on as u32 * 100
+    }
+
+    pub fn off_millis(&self) -> u32 {
+        self.off as u32 * 100
+    }
+}
+
+impl Default for PumpDelays {
+    fn default() -> Self {
+        Self { on: 20, off: 10 }
+    }
+}
+
+impl uDebug for PumpDelays {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, r#"{{"on": {}, "off": {}}}"#, self.on, self.off)
+    }
+}
+
+/// Levels of the leak sensor, kept in steps of 4 ADC counts.
+#[derive(Clone, Copy, PartialEq)]
+pub struct LeakThresholds {
+    on: u8,
+    off: u8,
+}
+
+impl LeakThresholds {
+    /// Level from which the sensor reads wet.
+    pub fn on_level(&self) -> u16 {
+        self.on as u16 * 4
+    }
+
+    /// Level below which a wet sensor reads dry again.
+    pub fn off_level(&self) -> u16 {
+        self.off as u16 * 4
+    }
+
+    /// Sets both levels in ADC counts, `off` has to be below `on` and `on` below an open circuit,
+    /// both compared as they are kept. An `off` level of 0 would never let a wet sensor dry.
+    pub fn set(&mut self, on: u16, off: u16) -> Result<(), Error> {
+        if off / 4 == 0 || off / 4 >= on / 4 || on >= leak::MISSING {
+            return Err(Error::Threshold);
+        }
+        self.on = (on / 4) as u8;
+        self.off = (off / 4) as u8;
+        Ok(())
+    }
+}
+
+impl Default for LeakThresholds {
+    fn default() -> Self {
+        Self { on: 15, off: 10 }
+    }
+}
+
+/// What the controller does when a leak zone turns wet, ordered from mild to severe.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum LeakAction {
+    /// Raise the alarm, the valves keep running.
+    Alarm,
+    /// Keep the einlass, and with it the pump, closed.
+    CloseInlet,
+    /// Stop the job and idle all valves.
+    Idle,
+}
+
+impl LeakAction {
+    pub fn from_u8(action: u8) -> Result<Self, Error> {
+        match action {
+            0 => Ok(LeakAction::Alarm),
+            1 => Ok(LeakAction::CloseInlet),
+            2 => Ok(LeakAction::Idle),
+            _ => Err(Error::Action),
+        }
+    }
+}
+
+impl uDebug for LeakAction {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        let name = match self {
+            LeakAction::Alarm => progmem!(b"alarm"),
+            LeakAction::CloseInlet => progmem!(b"close_inlet"),
+            LeakAction::Idle => progmem!(b"idle"),
+        };
+        uwrite!(f, "{}", name)
+    }
+}
+
+/// Thresholds and action of one leak sensor.
+#[derive(Clone, Copy, PartialEq)]
+pub struct LeakZone {
+    pub thresholds: LeakThresholds,
+    pub action: LeakAction,
+}
+
+impl LeakZone {
+    /// Sets the thresholds in ADC counts and the action, see `LeakAction::from_u8`.
+    pub fn set(&mut self, on: u16, off: u16, action: u8) -> Result<(), Error> {
+        let action = LeakAction::from_u8(action)?;
+        self.thresholds.set(on, off)?;
+        self.action = action;
+        Ok(())
+    }
+}
+
+impl uDebug for LeakZone {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(
+            f,
+            r#"{{"on": {}, "off": {}, "action": "{:?}"}}"#,
+            self.thresholds.on_level(),
+            self.thresholds.off_level(),
+            self.action
+        )
+    }
+}
+
+/// Settings that can be changed at runtime over the serial protocol.
+///
+/// The config is kept in the EEPROM by `store::ConfigStore` and
+/// survives a reset of the controller.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Config {
+    pub valves: ValveTable,
+    pub recipes: [Recipe; RECIPE_COUNT],
+    pub interlocks: Interlocks,
+    pub settle: SettleTimes,
+    /// Seconds a valve may disagree with its feedback before it counts as stuck.
+    pub stuck_timeout: u8,
+    pub pump: PumpDelays,
+    /// One entry for every leak zone, in the order of `leak::zone_name`.
+    pub leak: [LeakZone; ZONE_COUNT],
+}
+
+impl Config {
+    /// Sets the valve pattern of `job`, if the interlocks allow it.
+    pub fn set_valves(&mut self, job: u8, pattern: u8) -> Result<(), Error> {
+        self.interlocks.check(pattern).map_err(Error::Forbidden)?;
+        self.valves.set_pattern(job, pattern)
+    }
+
+    /// Replaces or appends step `index` of `recipe`, if the interlocks allow its pattern.
+    pub fn set_step(&mut self, recipe: u8, index: u8, step: Step) -> Result<(), Error> {
+        self.interlocks
+            .check(step.pattern)
+            .map_err(Error::Forbidden)?;
+        self.recipes
+            .get_mut(recipe as usize)
+            .ok_or(Error::Step(recipe::Error::Recipe))?
+            .set_step(index, step)
+            .map_err(Error::Step)
+    }
+
+    pub fn set_stuck_timeout(&mut self, seconds: u8) -> Result<(), Error> {
+        if seconds == 0 {
+            return Err(Error::Timeout);
+        }
+        self.stuck_timeout = seconds;
+        Ok(())
+    }
+
+    /// Sets the interlock rule at `index`, unless it forbids a pattern of the valve table,
+    /// of a recipe step or the `manual` pattern the valves are set to.
+    pub fn set_interlock(&mut self, index: u8, rule: u8, manual: Option<u8>) -> Result<(), Error> {
+        let mut interlocks = self.interlocks;
+        interlocks.set_rule(index, rule)?;
+        let table = [
+            self.valves.pattern(JOB_IDLE),
+            self.valves.pattern(JOB_FILTER),
+        ];
+        let steps = self
+            .recipes
+            .iter()
+            .flat_map(|recipe| (0..recipe.len()).filter_map(move |i| recipe.step(i)))
+            .map(|step| step.pattern);
+        for pattern in table.iter().copied().chain(steps).chain(manual) {
+            interlocks.check(pattern).map_err(Error::Forbidden)?;
+        }
+        self.interlocks = interlocks;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONFIG_LEN] {
+        let mut buf = [0; CONFIG_LEN];
+        buf[..JOB_COUNT].copy_from_slice(&self.valves.0);
+        for (r, recipe) in self.recipes.iter().enumerate() {
+            let chunk = &mut buf[RECIPES + r * RECIPE_LEN..][..RECIPE_LEN];
+            chunk[0] = recipe.len();
+            for i in 0..recipe.len() {
+                let step = recipe.step(i).unwrap();
+                let seconds = step.duration.seconds().to_le_bytes();
+                chunk[1 + i as usize * 3..][..3].copy_from_slice(&[
+                    step.pattern,
+                    seconds[0],
+                    seconds[1],
+                ]);
+            }
+        }
+        buf[INTERLOCKS..][..MAX_INTERLOCKS].copy_from_slice(&self.interlocks.0);
+        buf[SETTLE..][..VALVE_COUNT].copy_from_slice(&self.settle.0);
+        buf[STUCK] = self.stuck_timeout;
+        buf[PUMP] = self.pump.on;
+        buf[PUMP + 1] = self.pump.off;
+        let mut actions = 0;
+        for (i, zone) in self.leak.iter().enumerate() {
+            buf[LEAK + i * 2] = zone.thresholds.on;
+            buf[LEAK + i * 2 + 1] = zone.thresholds.off;
+            actions |= (zone.action as u8) << (i * 2);
+        }
+        buf[LEAK + ZONE_COUNT * 2] = actions;
+        buf
+    }
+
+    /// CRC of the serialized config, it changes with every change of the config.
+    pub fn crc(&self) -> u16 {
+        crc16(&self.to_bytes())
+    }
+
+    /// Restores a config written by `to_bytes`, `None` if the data is not a valid config.
+    pub fn from_bytes(buf: &[u8; CONFIG_LEN]) -> Option<Self> {
+        let mut config = Self::default();
+        // the rules come first, the valve patterns and steps are checked against them
+        for i in 0..MAX_INTERLOCKS {
+            config
+                .interlocks
+                .set_rule(i as u8, buf[INTERLOCKS + i])
+                .ok()?;
+        }
+        for job in JOB_IDLE..JOB_CLEAN {
+            config.set_valves(job, buf[job as usize]).ok()?;
+        }
+        config.recipes = [Recipe::empty(); RECIPE_COUNT];
+        for r in 0..RECIPE_COUNT {
+            let chunk = &buf[RECIPES + r * RECIPE_LEN..][..RECIPE_LEN];
+            for i in 0..chunk[0].min(MAX_STEPS as u8) {
+                let step = &chunk[1 + i as usize * 3..][..3];
+                let seconds = i16::from_le_bytes([step[1], step[2]]);
+                config
+                    .set_step(r as u8, i, Step::new(step[0], Duration(seconds)))
+                    .ok()?;
+            }
+        }
+        config
+            .settle
+            .0
+            .copy_from_slice(&buf[SETTLE..][..VALVE_COUNT]);
+        config.set_stuck_timeout(buf[STUCK]).ok()?;
+        config.pump = PumpDelays {
+            on: buf[PUMP],
+            off: buf[PUMP + 1],
+        };
+        let actions = buf[LEAK + ZONE_COUNT * 2];
+        for (i, zone) in config.leak.iter_mut().enumerate() {
+            let levels = &buf[LEAK + i * 2..][..2];
+            zone.thresholds
+                .set(levels[0] as u16 * 4, levels[1] as u16 * 4)
+                .ok()?;
+            zone.action = LeakAction::from_u8(actions >> (i * 2) & 0b11).ok()?;
+        }
+        Some(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            valves: ValveTable::default(),
+            recipes: [
+                Recipe::from_steps(&[Step::new(ValveMask::CLEAN, Duration(10))]),
+                Recipe::from_steps(&[Step::new(ValveMask::CLEAN, Duration(5))]),
+            ],
+            interlocks: Interlocks::default(),
+            settle: SettleTimes::default(),
+            stuck_timeout: 5,
+            pump: PumpDelays::default(),
+            leak: [
+                LeakZone {
+                    thresholds: LeakThresholds::default(),
+                    action: LeakAction::Idle,
+                },
+                LeakZone {
+                    thresholds: LeakThresholds::default(),
+                    action: LeakAction::Idle,
+                },
+                LeakZone {
+                    thresholds: LeakThresholds::default(),
+                    action: LeakAction::CloseInlet,
+                },
+            ],
+        }
+    }
+}
@@ -1,20 +1,45 @@
+use crate::actuator::{Actuator, ActuatorState, Position};
+use crate::config::{
+    Config, Interlocks, LeakAction, LeakZone, JOB_FILTER, JOB_IDLE, VALVE_COUNT,
+};
+use crate::diag::Stack;
+use crate::leak::{zone_name, LeakSensor, ZONE_COUNT};
+use crate::power::Power;
+use crate::recipe::CleanState;
+use crate::reset::ResetInfo;
 use crate::time::{DateTime, Time};
-use arduino_hal::port::mode::Output;
-use arduino_hal::port::Pin;
 use ufmt::derive::uDebug;
 use ufmt::{uDebug, uWrite, uwrite};
 
-pub struct Control<P1, P2, P3, P4> {
+pub struct Control<A1, A2, A3, A4, PU> {
     pub start_time: DateTime,
     pub current_time: DateTime,
-    pub ventil_gruppe: VentilGruppe<P1, P2, P3, P4>,
+    pub ventil_gruppe: VentilGruppe<A1, A2, A3, A4, PU>,
     pub control_mode: ControlMode,
     pub already_cleaned: bool,
     pub distance: Option<u16>,
     pub water_breach: Waterbreach,
+    pub leak: [LeakSensor; ZONE_COUNT],
+    pub config: Config,
+    pub interlock: InterlockViolation,
+    /// Why the controller started.
+    pub reset: ResetInfo,
+    pub stack: Stack,
+    pub power: Power,
+    /// What paced the last cycle.
+    pub tick: Tick,
 }
 
-impl<P1, P2, P3, P4> Control<P1, P2, P3, P4> {
+pub enum Error {
+    /// The valve pattern is forbidden by the interlock rule.
+    Interlock(u8),
+    /// The leak alarm has to be acknowledged before it can be cleared.
+    Unacknowledged,
+    /// The leak sensor is still wet.
+    Wet,
+}
+
+impl<A1, A2, A3, A4, PU> Control<A1, A2, A3, A4, PU> {
     pub fn needs_cleaning(&self, time: Time) -> bool {
         if let ControlMode::Automatic(job, _) = &self.control_mode {
             if job == &Job::Idle
@@ -27,58 +52,353 @@ impl<P1, P2, P3, P4> Control<P1, P2, P3, P4> {
         }
         false
     }
+
+    /// Starts the cleaning recipe `recipe`, `None` if it has no steps.
+    pub fn start_clean(&self, recipe: u8) -> Option<Job> {
+        CleanState::start(&self.config.recipes, recipe, self.current_time).map(Job::Clean)
+    }
+
+    /// Clears an acknowledged leak alarm and returns to the mode from before the breach
+    /// if the alarm had idled the valves.
+    pub fn clear_breach(&mut self) -> Result<(), Error> {
+        if self.water_breach.is_latched() {
+            if let Some(mode) = self.water_breach.clear()? {
+                self.control_mode = mode;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A1, A2, A3, A4, PU> Control<A1, A2, A3, A4, PU>
+    where
+        A1: Actuator,
+        A2: Actuator,
+        A3: Actuator,
+        A4: Actuator,
+        PU: Actuator,
+{
+    /// Applies the valve pattern of `job`, taken from the valve table or the running recipe.
+    pub fn set_job_valves(&mut self, job: Job) {
+        let pattern = match job {
+            Job::Idle => self.config.valves.pattern(JOB_IDLE),
+            Job::Filter => self.config.valves.pattern(JOB_FILTER),
+            Job::Clean(state) => state.pattern(&self.config.recipes),
+        };
+        self.set_valves(pattern).ok();
+    }
+
+    /// Applies `pattern` if the interlocks allow it, without the valves held closed by a leak.
+    /// A forbidden pattern is recorded and all valves are closed instead.
+    pub fn set_valves(&mut self, pattern: u8) -> Result<(), Error> {
+        let pattern = pattern & !self.water_breach.blocked();
+        let result = self
+            .ventil_gruppe
+            .set_pattern(pattern, &self.config.interlocks);
+        if let Err(Error::Interlock(rule)) = result {
+            self.record_violation(pattern, rule);
+            self.ventil_gruppe.close_all();
+        }
+        result
+    }
+
+    /// Moves a running valve transition on, `now` in milliseconds.
+    pub fn poll_valves(&mut self, now: u32) {
+        self.ventil_gruppe.poll(now, &self.config);
+    }
+
+    /// Checks `pattern` against the interlocks without touching the valves.
+    pub fn check_valves(&mut self, pattern: u8) -> Result<(), Error> {
+        self.config.interlocks.check(pattern).map_err(|rule| {
+            self.record_violation(pattern, rule);
+            Error::Interlock(rule)
+        })
+    }
+
+    fn record_violation(&mut self, pattern: u8, rule: u8) {
+        self.interlock = InterlockViolation(Some((self.current_time, pattern, rule)));
+    }
 }
 
-impl<P1, P2, P3, P4> uDebug for Control<P1, P2, P3, P4>
+impl<A1, A2, A3, A4, PU> uDebug for Control<A1, A2, A3, A4, PU>
     where
-        P1: avr_hal_generic::port::PinOps,
-        P2: avr_hal_generic::port::PinOps,
-        P3: avr_hal_generic::port::PinOps,
-        P4: avr_hal_generic::port::PinOps,
+        A1: Actuator,
+        A2: Actuator,
+        A3: Actuator,
+        A4: Actuator,
+        PU: Actuator,
 {
     fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
         where
             W: uWrite,
     {
-        uwrite!(
-            f,
-            r#"{{
-            "start_time": "{}",
-            "current_time": "{}",
-            "ventile": {:?},
-            "mode": {:?},
-            "distance": "{}",
-            "water_breach": "{:?}"
-        }}"#,
-            self.start_time,
-            self.current_time,
-            self.ventil_gruppe,
-            self.control_mode,
-            self.distance.unwrap_or(0),
-            self.water_breach
-        )
-    }
-}
-
-pub struct Waterbreach(pub Option<DateTime>);
+        uwrite!(f, "{}{}", progmem!(br#"{
+            "start_time": ""#), self.start_time)?;
+        uwrite!(f, "{}{}", progmem!(br#"",
+            "current_time": ""#), self.current_time)?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+            "ventile": "#), self.ventil_gruppe)?;
+        uwrite!(f, "{}{:?}", progmem!(br#",
+            "mode": "#), self.control_mode)?;
+        uwrite!(f, "{}{}", progmem!(br#",
+            "distance": ""#), self.distance.unwrap_or(0))?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+            "water_breach": "#), self.water_breach)?;
+        uwrite!(f, "{}{:?}", progmem!(br#",
+            "leak": "#), self.leak)?;
+        uwrite!(f, "{}{:?}", progmem!(br#",
+            "interlock": ""#), self.interlock)?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+            "reset": "#), self.reset)?;
+        uwrite!(f, "{}{:?}", progmem!(br#",
+            "stack": "#), self.stack)?;
+        uwrite!(f, "{}{:?}", progmem!(br#",
+            "power": "#), self.power)?;
+        uwrite!(f, "{}{:?}", progmem!(br#",
+            "tick": ""#), self.tick)?;
+        uwrite!(f, "{}", progmem!(br#""
+        }"#))
+    }
+}
+
+/// What paces the control cycles.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Tick {
+    /// The millisecond tick of Timer0.
+    Millis,
+    /// The 1 Hz square wave of the RTC, with the sqw-tick feature.
+    Sqw,
+}
+
+impl uDebug for Tick {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+        where
+            W: uWrite,
+    {
+        let name = match self {
+            Tick::Millis => progmem!(b"millis"),
+            Tick::Sqw => progmem!(b"sqw"),
+        };
+        uwrite!(f, "{}", name)
+    }
+}
+
+pub const BREACH_HISTORY: usize = 4;
+/// Size of the alarm as it is kept across a reboot: state, action, zones,
+/// and zone and time of the newest breach.
+pub const BREACH_LEN: usize = 10;
+const NO_ZONE: u8 = 0xFF;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AlarmState {
+    /// The leak was detected and nobody has reacted yet.
+    Active,
+    /// Somebody has seen the alarm, its action stays in force until it is cleared.
+    Acknowledged,
+    Cleared,
+}
+
+impl uDebug for AlarmState {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+        where
+            W: uWrite,
+    {
+        let name = match self {
+            AlarmState::Active => progmem!(b"active"),
+            AlarmState::Acknowledged => progmem!(b"acknowledged"),
+            AlarmState::Cleared => progmem!(b"cleared"),
+        };
+        uwrite!(f, "{}", name)
+    }
+}
+
+/// The leak alarm. It latches until it is acknowledged and cleared with all sensors dry.
+pub struct Waterbreach {
+    pub state: AlarmState,
+    /// Leak zones that read wet in the last cycle, one bit per zone.
+    pub wet: u8,
+    /// The most severe action of the zones that raised the alarm.
+    pub action: LeakAction,
+    /// Zones that raised the current alarm, one bit per zone.
+    zones: u8,
+    /// Mode to return to once an alarm that idled the valves is cleared.
+    previous_mode: ControlMode,
+    /// Start of the last breaches with their zone, newest first.
+    history: [Option<(DateTime, u8)>; BREACH_HISTORY],
+}
+
+impl Waterbreach {
+    pub const fn new() -> Self {
+        Self {
+            state: AlarmState::Cleared,
+            wet: 0,
+            action: LeakAction::Alarm,
+            zones: 0,
+            previous_mode: ControlMode::Off,
+            history: [None; BREACH_HISTORY],
+        }
+    }
+
+    /// Takes the wet zones of this cycle. Every zone that turns wet is recorded, raises the
+    /// alarm again and can make its action more severe, the alarm keeps its history.
+    pub fn sense(
+        &mut self,
+        wet: u8,
+        zones: &[LeakZone; ZONE_COUNT],
+        now: DateTime,
+        mode: ControlMode,
+    ) {
+        self.wet = wet;
+        for (zone, LeakZone { action, .. }) in zones.iter().enumerate() {
+            let bit = 1 << zone;
+            if wet & bit == 0 || self.zones & bit != 0 {
+                continue;
+            }
+            if self.state == AlarmState::Cleared {
+                self.action = LeakAction::Alarm;
+            }
+            if *action == LeakAction::Idle && self.action != LeakAction::Idle {
+                self.previous_mode = mode;
+            }
+            if *action > self.action {
+                self.action = *action;
+            }
+            self.state = AlarmState::Active;
+            self.zones |= bit;
+            self.history.copy_within(..BREACH_HISTORY - 1, 1);
+            self.history[0] = Some((now, zone as u8));
+        }
+    }
+
+    /// The alarm as it is kept across a reboot, with only the newest breach of the history.
+    pub fn to_bytes(&self) -> [u8; BREACH_LEN] {
+        let mut buf = [0; BREACH_LEN];
+        buf[0] = self.state as u8;
+        buf[1] = self.action as u8;
+        buf[2] = self.zones;
+        buf[3] = NO_ZONE;
+        if let Some((time, zone)) = self.history[0] {
+            buf[3] = zone;
+            buf[4..].copy_from_slice(&time.to_bytes());
+        }
+        buf
+    }
+
+    /// Restores an alarm written by `to_bytes`. Its zones count as wet until the sensors
+    /// have been read, clearing it returns to the mode the controller boots in.
+    pub fn from_bytes(buf: &[u8; BREACH_LEN]) -> Option<Self> {
+        let mut breach = Self::new();
+        breach.state = match buf[0] {
+            0 => AlarmState::Active,
+            1 => AlarmState::Acknowledged,
+            2 => AlarmState::Cleared,
+            _ => return None,
+        };
+        breach.action = LeakAction::from_u8(buf[1]).ok()?;
+        breach.zones = buf[2];
+        breach.wet = breach.zones;
+        if buf[3] != NO_ZONE {
+            let mut time = [0; 6];
+            time.copy_from_slice(&buf[4..]);
+            breach.history[0] = Some((DateTime::from_bytes(&time), buf[3]));
+        }
+        breach.previous_mode = ControlMode::Automatic(Job::Idle, Job::Idle);
+        Some(breach)
+    }
+
+    /// Zones that raised the current alarm, one bit per zone.
+    pub fn zones(&self) -> u8 {
+        self.zones
+    }
+
+    pub fn is_latched(&self) -> bool {
+        self.state != AlarmState::Cleared
+    }
+
+    /// Whether the alarm holds all valves idle.
+    pub fn holds_idle(&self) -> bool {
+        self.is_latched() && self.action == LeakAction::Idle
+    }
+
+    /// Valves the alarm keeps closed while the controller goes on running.
+    pub fn blocked(&self) -> u8 {
+        if self.is_latched() && self.action == LeakAction::CloseInlet {
+            ValveMask::EINLASS | ValveMask::PUMP
+        } else {
+            0
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        if self.state == AlarmState::Active {
+            self.state = AlarmState::Acknowledged;
+        }
+    }
+
+    /// Ends an acknowledged alarm once all sensors are dry.
+    /// Returns the mode from before the breach if the alarm had idled the valves.
+    pub fn clear(&mut self) -> Result<Option<ControlMode>, Error> {
+        match self.state {
+            AlarmState::Active => Err(Error::Unacknowledged),
+            _ if self.wet != 0 => Err(Error::Wet),
+            _ => {
+                let idled = self.holds_idle();
+                self.state = AlarmState::Cleared;
+                self.zones = 0;
+                Ok(if idled { Some(self.previous_mode) } else { None })
+            }
+        }
+    }
+}
 
 impl uDebug for Waterbreach {
     fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
         where
             W: uWrite,
     {
-        if self.0.is_some() {
-            return uwrite!(f, "{}", self.0.unwrap());
+        uwrite!(f, "{}{:?}", progmem!(br#"{"state": ""#), self.state)?;
+        uwrite!(f, "{}{:?}", progmem!(br#"", "action": ""#), self.action)?;
+        uwrite!(f, "{}{}", progmem!(br#"", "wet": "#), self.wet)?;
+        uwrite!(f, "{}", progmem!(br#", "history": ["#))?;
+        for (i, (time, zone)) in self.history.iter().flatten().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            uwrite!(f, "{}{}", progmem!(br#"{"time": ""#), time)?;
+            uwrite!(f, "{}", progmem!(br#"", "zone": ""#))?;
+            if let Some(name) = zone_name(*zone) {
+                uwrite!(f, "{}", name)?;
+            }
+            f.write_str("\"}")?;
         }
-        uwrite!(f, "None")
+        f.write_str("]}")
     }
 }
 
-#[derive(PartialEq)]
+/// The last valve pattern rejected by the interlocks, with the rule it broke.
+pub struct InterlockViolation(pub Option<(DateTime, u8, u8)>);
+
+impl uDebug for InterlockViolation {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+        where
+            W: uWrite,
+    {
+        if let Some((time, pattern, rule)) = self.0 {
+            return uwrite!(f, "{}{}{}{}{}", time, progmem!(b" pattern "), pattern,
+                progmem!(b" rule "), rule);
+        }
+        uwrite!(f, "{}", progmem!(b"None"))
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
 pub enum ControlMode {
     Automatic(Job, Job),
     Manual(ManualControl),
     Breach,
+    /// A valve is stuck or the leak sensor is missing,
+    /// all valves are closed until the fault is cleared.
+    Fault,
     Off,
 }
 
@@ -89,165 +409,344 @@ impl uDebug for ControlMode {
     {
         match self {
             ControlMode::Automatic(current_job, next_job) => {
-                uwrite!(f,
-            r#"{{
-            "name": "{}",
+                uwrite!(f, "{}{:?}", progmem!(br#"{
+            "name": "automatic",
             "jobs": [
-                "{:?}",
-                "{:?}"
+                ""#), current_job)?;
+                uwrite!(f, "{}{:?}", progmem!(br#"",
+                ""#), next_job)?;
+                uwrite!(f, "{}", progmem!(br#""
             ]
-            }}"#,
-                "automatic", current_job, next_job)
+            }"#))
             }
             ControlMode::Manual(manctrl) => {
-                uwrite!(f,
-            r#"{{
-            "name": "{}",
+                uwrite!(f, "{}{:?}", progmem!(br#"{
+            "name": "manual",
             "jobs": [
-                "{:?}"
+                ""#), manctrl)?;
+                uwrite!(f, "{}", progmem!(br#""
             ]
-            }}"#,
-                "manual", manctrl)
+            }"#))
             }
             ControlMode::Breach => {
-                uwrite!(f,
-            r#"{{
-            "name": "{}",
+                uwrite!(f, "{}", progmem!(br#"{
+            "name": "breach",
             "jobs": []
-            }}"#,
-                "breach")
+            }"#))
+            }
+            ControlMode::Fault => {
+                uwrite!(f, "{}", progmem!(br#"{
+            "name": "fault",
+            "jobs": []
+            }"#))
             }
             ControlMode::Off => {
-                uwrite!(f,
-            r#"{{
-            "name": "{}",
+                uwrite!(f, "{}", progmem!(br#"{
+            "name": "off",
             "jobs": []
-            }}"#,
-                "off")
+            }"#))
             }
         }
     }
 }
 
-#[derive(uDebug, PartialEq)]
+#[derive(uDebug, PartialEq, Clone, Copy)]
 pub enum ManualControl {
     CurrentJob(Job),
-    Bridged(bool, bool, bool, bool),
+    /// Valves set directly to a pattern.
+    Bridged(u8),
 }
 
-#[derive(PartialEq, uDebug)]
+#[derive(PartialEq, Clone, Copy, uDebug)]
 pub enum Job {
     Idle,
     Filter,
-    Clean(DateTime),
+    Clean(CleanState),
+}
+
+/// Bit positions of the valves in a valve pattern.
+pub struct ValveMask;
+impl ValveMask {
+    pub const EINLASS: u8 = 0b0001;
+    pub const ABWASSER: u8 = 0b0010;
+    pub const FILTERWASSER: u8 = 0b0100;
+    pub const BRIDGE: u8 = 0b1000;
+    pub const PUMP: u8 = 0b1_0000;
+    pub const VALVES: u8 = 0b1111;
+    pub const ALL: u8 = Self::VALVES | Self::PUMP;
+    /// Valves that have to be open for the pump to run.
+    pub const PUMP_SUPPLY: u8 = Self::EINLASS | Self::FILTERWASSER;
+
+    pub const IDLE: u8 = 0;
+    pub const FILTER: u8 = Self::EINLASS | Self::ABWASSER | Self::FILTERWASSER | Self::PUMP;
+    pub const CLEAN: u8 = Self::EINLASS | Self::ABWASSER | Self::BRIDGE;
+
+    /// Whether `pattern` only uses known bits and only runs the pump with its supply open.
+    pub fn is_valid(pattern: u8) -> bool {
+        pattern & !Self::ALL == 0
+            && (pattern & Self::PUMP == 0 || pattern & Self::PUMP_SUPPLY == Self::PUMP_SUPPLY)
+    }
 }
 
-pub struct VentilGruppe<P1, P2, P3, P4> {
-    pub einlass: Ventil<P1>,
-    pub abwasser: Ventil<P2>,
-    pub filterwasser: Ventil<P3>,
-    pub bridge: Ventil<P4>,
+pub struct VentilGruppe<A1, A2, A3, A4, PU> {
+    pub einlass: A1,
+    pub abwasser: A2,
+    pub filterwasser: A3,
+    pub bridge: A4,
+    pub pump: PU,
+    target: u8,
+    next_switch: u32,
+    pump_at: Option<u32>,
+    dry_run: bool,
+    /// Since when every valve disagrees with its feedback, in the order of the valve bits.
+    mismatch_since: [Option<u32>; VALVE_COUNT],
+    stuck: u8,
 }
 
-impl<P1, P2, P3, P4> VentilGruppe<P1, P2, P3, P4>
+impl<A1, A2, A3, A4, PU> VentilGruppe<A1, A2, A3, A4, PU>
     where
-        P1: avr_hal_generic::port::PinOps,
-        P2: avr_hal_generic::port::PinOps,
-        P3: avr_hal_generic::port::PinOps,
-        P4: avr_hal_generic::port::PinOps,
+        A1: Actuator,
+        A2: Actuator,
+        A3: Actuator,
+        A4: Actuator,
+        PU: Actuator,
 {
-    pub fn new(
-        einlass: Pin<Output, P1>,
-        abwasser: Pin<Output, P2>,
-        filterwasser: Pin<Output, P3>,
-        bridge: Pin<Output, P4>,
-    ) -> Self {
+    pub fn new(einlass: A1, abwasser: A2, filterwasser: A3, bridge: A4, pump: PU) -> Self {
         Self {
-            einlass: Ventil::new(einlass),
-            abwasser: Ventil::new(abwasser),
-            filterwasser: Ventil::new(filterwasser),
-            bridge: Ventil::new(bridge),
+            einlass,
+            abwasser,
+            filterwasser,
+            bridge,
+            pump,
+            target: ValveMask::IDLE,
+            next_switch: 0,
+            pump_at: None,
+            dry_run: false,
+            mismatch_since: [None; VALVE_COUNT],
+            stuck: 0,
         }
     }
 
-    pub fn set_clean(&mut self) {
-        self.einlass.open();
-        self.abwasser.open();
-        self.filterwasser.close();
-        self.bridge.open();
+    /// Starts the transition to `pattern`, unless one of the interlocks forbids it.
+    ///
+    /// The valves are switched by `poll`, one at a time.
+    pub fn set_pattern(&mut self, pattern: u8, interlocks: &Interlocks) -> Result<(), Error> {
+        interlocks.check(pattern).map_err(Error::Interlock)?;
+        self.target = pattern;
+        Ok(())
     }
 
-    pub fn set_filter(&mut self) {
-        self.einlass.open();
-        self.abwasser.open();
-        self.filterwasser.open();
-        self.bridge.close();
+    pub fn close_all(&mut self) {
+        self.target = ValveMask::IDLE;
     }
 
-    pub fn set_idle(&mut self) {
-        self.einlass.close();
-        self.abwasser.close();
-        self.filterwasser.close();
-        self.bridge.close();
+    /// Reports the dry-run protection input, the pump stops right away while it is set.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
     }
-}
 
-impl<P1, P2, P3, P4> uDebug for VentilGruppe<P1, P2, P3, P4>
-    where
-        P1: avr_hal_generic::port::PinOps,
-        P2: avr_hal_generic::port::PinOps,
-        P3: avr_hal_generic::port::PinOps,
-        P4: avr_hal_generic::port::PinOps,
-{
-    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
-        where
-            W: uWrite,
-    {
-        uwrite!(
-            f,
-            r#"{{
-            "einlass": {},
-            "abwasser": {},
-            "filterwasser": {},
-            "bridge": {}
-            }}"#,
-            self.einlass.is_open(),
-            self.abwasser.is_open(),
-            self.filterwasser.is_open(),
-            self.bridge.is_open()
-        )
+    /// Advances moving valves and switches the next valve of a running transition,
+    /// once the previous one has arrived and settled.
+    ///
+    /// All valves that have to close are closed before any valve is opened, so every
+    /// state in between is a subset of either the old or the new pattern.
+    /// The pump is stopped before any valve moves and only started once the valves
+    /// have been in place for the pump on delay.
+    ///
+    /// A valve whose feedback disagrees with its command for the stuck timeout
+    /// is marked as stuck.
+    pub fn poll(&mut self, now: u32, config: &Config) {
+        self.einlass.poll(now);
+        self.abwasser.poll(now);
+        self.filterwasser.poll(now);
+        self.bridge.poll(now);
+        self.pump.poll(now);
+
+        let mismatch = self.mismatch();
+        let stuck_after = config.stuck_timeout as u32 * 1000;
+        for (i, since) in self.mismatch_since.iter_mut().enumerate() {
+            let valve = 1 << i;
+            match *since {
+                _ if mismatch & valve == 0 => *since = None,
+                None => *since = Some(now),
+                Some(start) if now.wrapping_sub(start) >= stuck_after => self.stuck |= valve,
+                Some(_) => (),
+            }
+        }
+
+        // the pump never runs dry or against closed valves
+        if self.pump.state().is_commanded_open() && !self.pump_may_run() {
+            self.pump.set(false, now);
+            self.next_switch = now.wrapping_add(config.pump.off_millis());
+        }
+
+        if self.is_moving() || (now.wrapping_sub(self.next_switch) as i32) < 0 {
+            return;
+        }
+        let current = self.pattern() & ValveMask::VALVES;
+        let target = self.target & ValveMask::VALVES;
+        if current != target {
+            let closing = current & !target;
+            let opening = target & !current;
+            let (valve, open) = if closing != 0 {
+                (closing & closing.wrapping_neg(), false)
+            } else {
+                (opening & opening.wrapping_neg(), true)
+            };
+            self.set_valve(valve, open, now);
+            self.next_switch = now.wrapping_add(config.settle.millis(valve));
+            self.pump_at = None;
+        } else if !self.pump.state().is_commanded_open() && self.pump_may_run() {
+            match self.pump_at {
+                None => self.pump_at = Some(now.wrapping_add(config.pump.on_millis())),
+                Some(at) if (now.wrapping_sub(at) as i32) >= 0 => {
+                    self.pump.set(true, now);
+                    self.pump_at = None;
+                }
+                Some(_) => (),
+            }
+        } else {
+            self.pump_at = None;
+        }
     }
-}
 
-pub struct Ventil<NR> {
-    pin: Pin<Output, NR>,
-    open: bool,
-}
+    /// Whether all valves have reached the pattern of the last `set_pattern`.
+    /// The pump follows on its own delay and is not waited for.
+    pub fn is_settled(&self) -> bool {
+        self.pattern() & ValveMask::VALVES == self.target & ValveMask::VALVES && !self.is_moving()
+    }
 
-impl<NR> Ventil<NR>
-    where
-        NR: avr_hal_generic::port::PinOps,
-{
-    pub fn new(pin: Pin<Output, NR>) -> Self {
-        Self { pin, open: false }
+    /// Whether nothing is timed in milliseconds: the valves are settled, the pump runs or
+    /// may not run and no feedback mismatch is being timed.
+    pub fn is_at_rest(&self) -> bool {
+        self.is_settled()
+            && (self.pump.state().is_commanded_open() || !self.pump_may_run())
+            && self.mismatch_since.iter().all(Option::is_none)
     }
 
-    pub fn open(&mut self) {
-        self.pin.set_high();
-        self.open = true;
+    pub fn target(&self) -> u8 {
+        self.target
     }
-    pub fn close(&mut self) {
-        self.pin.set_low();
-        self.open = false;
+
+    /// The valves and the pump that were last told to open or run.
+    pub fn pattern(&self) -> u8 {
+        let mut pattern = self.valves_where(|v| v.state().is_commanded_open());
+        if self.pump.state().is_commanded_open() {
+            pattern |= ValveMask::PUMP;
+        }
+        pattern
     }
-    pub fn set(&mut self, b: bool) {
-        if b {
-            self.open();
-        } else {
-            self.close();
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Valves that did not follow their command, latched until `clear_stuck`.
+    pub fn stuck(&self) -> u8 {
+        self.stuck
+    }
+
+    /// Marks `stuck` valves as stuck again, as they were before a reboot.
+    pub fn restore_stuck(&mut self, stuck: u8) {
+        self.stuck = stuck & ValveMask::VALVES;
+    }
+
+    pub fn clear_stuck(&mut self) {
+        self.stuck = 0;
+        self.mismatch_since = [None; VALVE_COUNT];
+    }
+
+    /// Whether the pump is wanted and its supply valves are open and at rest,
+    /// going by their feedback where there is one.
+    fn pump_may_run(&self) -> bool {
+        let supplied = self.valves_where(|v| {
+            v.state() == ActuatorState::Open && v.actual().unwrap_or(true)
+        });
+        self.target & ValveMask::PUMP != 0
+            && !self.dry_run
+            && supplied & ValveMask::PUMP_SUPPLY == ValveMask::PUMP_SUPPLY
+            && self.target & ValveMask::VALVES == self.pattern() & ValveMask::VALVES
+    }
+
+    fn is_moving(&self) -> bool {
+        self.valves_where(|v| v.state().is_moving()) != 0
+    }
+
+    /// Valves at rest whose feedback disagrees with their command.
+    fn mismatch(&self) -> u8 {
+        self.valves_where(|v| {
+            let state = v.state();
+            !state.is_moving() && v.actual().map_or(false, |a| a != state.is_commanded_open())
+        })
+    }
+
+    fn valves_where(&self, f: impl Fn(&dyn Actuator) -> bool) -> u8 {
+        let mut pattern = 0;
+        if f(&self.einlass) {
+            pattern |= ValveMask::EINLASS;
         }
+        if f(&self.abwasser) {
+            pattern |= ValveMask::ABWASSER;
+        }
+        if f(&self.filterwasser) {
+            pattern |= ValveMask::FILTERWASSER;
+        }
+        if f(&self.bridge) {
+            pattern |= ValveMask::BRIDGE;
+        }
+        pattern
     }
-    pub fn is_open(&self) -> bool {
-        self.open
+
+    fn set_valve(&mut self, valve: u8, open: bool, now: u32) {
+        match valve {
+            ValveMask::EINLASS => self.einlass.set(open, now),
+            ValveMask::ABWASSER => self.abwasser.set(open, now),
+            ValveMask::FILTERWASSER => self.filterwasser.set(open, now),
+            ValveMask::BRIDGE => self.bridge.set(open, now),
+            _ => (),
+        }
+    }
+}
+
+impl<A1, A2, A3, A4, PU> uDebug for VentilGruppe<A1, A2, A3, A4, PU>
+    where
+        A1: Actuator,
+        A2: Actuator,
+        A3: Actuator,
+        A4: Actuator,
+        PU: Actuator,
+{
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+        where
+            W: uWrite,
+    {
+        uwrite!(f, "{}{:?}", progmem!(br#"{
+            "einlass": ""#), self.einlass.state())?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+            "abwasser": ""#), self.abwasser.state())?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+            "filterwasser": ""#), self.filterwasser.state())?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+            "bridge": ""#), self.bridge.state())?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+            "pump": ""#), self.pump.state())?;
+        uwrite!(f, "{}{}", progmem!(br#"",
+            "dry_run": "#), self.dry_run)?;
+        uwrite!(f, "{}{}", progmem!(br#",
+            "target": "#), self.target)?;
+        uwrite!(f, "{}{:?}", progmem!(br#",
+            "actual": {
+                "einlass": ""#), Position(self.einlass.actual()))?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+                "abwasser": ""#), Position(self.abwasser.actual()))?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+                "filterwasser": ""#), Position(self.filterwasser.actual()))?;
+        uwrite!(f, "{}{:?}", progmem!(br#"",
+                "bridge": ""#), Position(self.bridge.actual()))?;
+        uwrite!(f, "{}{}", progmem!(br#""
+            },
+            "stuck": "#), self.stuck)?;
+        uwrite!(f, "{}", progmem!(br#"
+            }"#))
     }
 }
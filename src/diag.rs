@@ -0,0 +1,215 @@
+use crate::config::CONFIG_VERSION;
+use crate::leak::{zone_name, ZONE_COUNT};
+use crate::progmem::PmStr;
+use crate::rtc::MAX_REGISTERS;
+use ufmt::{uDebug, uWrite, uwrite};
+
+/// Address of the stack pointer, SPL followed by SPH, in the data space.
+const SP: *const u16 = 0x5D as *const u16;
+/// Fills the RAM the stack has not reached yet.
+const PAINT: u8 = 0xA5;
+/// Bytes below the stack pointer left alone while painting, for the frame of `paint_stack`.
+const PAINT_SLACK: u16 = 32;
+/// A stack margin below this many bytes is logged as a warning.
+pub const STACK_WARNING: u16 = 128;
+
+/// Cargo features of the build, each with whether it is enabled.
+fn features() -> [(PmStr, bool); 6] {
+    [
+        (
+            progmem!(b"valve-feedback"),
+            cfg!(feature = "valve-feedback"),
+        ),
+        (progmem!(b"motor-valves"), cfg!(feature = "motor-valves")),
+        (progmem!(b"pump"), cfg!(feature = "pump")),
+        (
+            progmem!(b"leak-excitation"),
+            cfg!(feature = "leak-excitation"),
+        ),
+        (progmem!(b"sqw-tick"), cfg!(feature = "sqw-tick")),
+        (progmem!(b"ds3231"), cfg!(feature = "ds3231")),
+    ]
+}
+
+extern "C" {
+    /// End of the static data, provided by the linker script.
+    static __heap_start: u8;
+}
+
+/// Bytes between the end of the static data and the stack pointer.
+pub fn free_ram() -> u16 {
+    stack_pointer().wrapping_sub(heap_start())
+}
+
+/// Fills the free RAM below the stack with a pattern, so `stack_margin` can tell how deep
+/// the stack has grown since. Has to be called at boot, before interrupts are enabled.
+pub fn paint_stack() {
+    let end = stack_pointer().wrapping_sub(PAINT_SLACK);
+    for address in heap_start()..end {
+        // SAFETY: nothing lives between the static data and the stack
+        unsafe { core::ptr::write_volatile(address as usize as *mut u8, PAINT) };
+    }
+}
+
+/// Bytes above the static data the stack has never reached since it was painted.
+pub fn stack_margin() -> u16 {
+    let start = heap_start();
+    let end = stack_pointer();
+    let mut address = start;
+    // SAFETY: the RAM between the static data and the stack may always be read
+    while address < end
+        && unsafe { core::ptr::read_volatile(address as usize as *const u8) } == PAINT
+    {
+        address += 1;
+    }
+    address - start
+}
+
+fn stack_pointer() -> u16 {
+    // SAFETY: SP can always be read
+    unsafe { core::ptr::read_volatile(SP) }
+}
+
+fn heap_start() -> u16 {
+    // SAFETY: `__heap_start` is only used for its address
+    unsafe { &__heap_start as *const u8 as usize as u16 }
+}
+
+/// RAM left for the stack, as reported in the status.
+#[derive(Clone, Copy)]
+pub struct Stack {
+    /// Bytes between the static data and the stack pointer now.
+    pub free: u16,
+    /// The least free bytes since the start, the high-water mark of the stack.
+    pub margin: u16,
+}
+
+impl Stack {
+    pub fn measure() -> Self {
+        Self {
+            free: free_ram(),
+            margin: stack_margin(),
+        }
+    }
+}
+
+impl uDebug for Stack {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, "{}{}", progmem!(br#"{"free": "#), self.free)?;
+        uwrite!(f, "{}{}", progmem!(br#", "margin": "#), self.margin)?;
+        f.write_str("}")
+    }
+}
+
+/// Which firmware runs on which board, as printed by `INFO`.
+pub struct Info;
+
+impl uDebug for Info {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(
+            f,
+            "{}{}",
+            progmem!(br#"{"version": ""#),
+            progmem!(env!("CARGO_PKG_VERSION").as_bytes())
+        )?;
+        uwrite!(
+            f,
+            "{}{}",
+            progmem!(br#"", "git": ""#),
+            progmem!(env!("GIT_HASH").as_bytes())
+        )?;
+        uwrite!(
+            f,
+            "{}{}",
+            progmem!(br#"", "mcu": "atmega328p", "board": "arduino-nano", "config": "#),
+            CONFIG_VERSION
+        )?;
+        uwrite!(f, "{}", progmem!(br#", "features": ["#))?;
+        let mut first = true;
+        for (name, _) in features().iter().filter(|(_, enabled)| *enabled) {
+            if !first {
+                f.write_str(", ")?;
+            }
+            uwrite!(f, "\"{}\"", *name)?;
+            first = false;
+        }
+        uwrite!(f, "{}{}", progmem!(br#"], "free_ram": "#), free_ram())?;
+        f.write_str("}")
+    }
+}
+
+/// Raw readings of the last cycle, as printed by `DIAG`.
+pub struct Diagnostics {
+    /// A single ADC sample of every leak input.
+    pub adc: [u16; ZONE_COUNT],
+    /// Timer1 counts of the last echo of the SR04, 4 µs each.
+    pub echo: Option<u16>,
+    /// Registers of the clock, the first `rtc_len` of them.
+    pub rtc: [u8; MAX_REGISTERS],
+    pub rtc_len: usize,
+    /// Temperature of the DS3231 in quarters of a degree Celsius.
+    pub rtc_temperature: Option<i16>,
+    /// Failed I2C transfers since the start.
+    pub i2c_errors: u16,
+}
+
+impl Diagnostics {
+    pub const fn new() -> Self {
+        Self {
+            adc: [0; ZONE_COUNT],
+            echo: None,
+            rtc: [0; MAX_REGISTERS],
+            rtc_len: 0,
+            rtc_temperature: None,
+            i2c_errors: 0,
+        }
+    }
+}
+
+impl uDebug for Diagnostics {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, "{}", progmem!(br#"{"adc": {"#))?;
+        for (i, level) in self.adc.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            if let Some(name) = zone_name(i as u8) {
+                uwrite!(f, "\"{}\": {}", name, level)?;
+            }
+        }
+        uwrite!(f, "{}", progmem!(br#"}, "echo": "#))?;
+        match self.echo {
+            Some(counts) => uwrite!(f, "{}", counts)?,
+            None => uwrite!(f, "{}", progmem!(b"null"))?,
+        }
+        uwrite!(
+            f,
+            "{}{:?}",
+            progmem!(br#", "rtc": "#),
+            &self.rtc[..self.rtc_len]
+        )?;
+        uwrite!(f, "{}", progmem!(br#", "rtc_temperature": "#))?;
+        match self.rtc_temperature {
+            Some(quarters) => {
+                let degrees = quarters.abs();
+                if quarters < 0 {
+                    f.write_str("-")?;
+                }
+                let hundredths = degrees % 4 * 25;
+                uwrite!(f, "{}.{}{}", degrees / 4, hundredths / 10, hundredths % 10)?
+            }
+            None => uwrite!(f, "{}", progmem!(b"null"))?,
+        }
+        uwrite!(f, "{}{}", progmem!(br#", "i2c_errors": "#), self.i2c_errors)?;
+        f.write_str("}")
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+use crate::rtc::{packed_bcd_to_decimal, Error, Rtc, SquareWave, MAX_REGISTERS};
 use crate::time::{Date, DateTime, Time};
 use embedded_hal::blocking::i2c::{Write, WriteRead};
 
@@ -30,12 +31,10 @@ impl BitFlags {
 
 pub const ADDR: u8 = 0b110_1000;
 
-pub enum Error {
-    I2C,
-}
-
 pub struct Ds1307<I2C> {
     i2c: I2C,
+    /// Failed I2C transfers.
+    errors: u16,
 }
 
 impl<I2C> Ds1307<I2C>
@@ -43,7 +42,7 @@ where
     I2C: Write + WriteRead,
 {
     pub fn new(i2c: I2C) -> Self {
-        Self { i2c }
+        Self { i2c, errors: 0 }
     }
 
     pub fn destroy(self) -> I2C {
@@ -60,12 +59,6 @@ where
         self.set_register_bit_flag(Register::SECONDS, BitFlags::CH)
     }
 
-    /// Halt the clock.
-    /// (Does not alter the device register if already halted).
-    pub fn start(&mut self) -> Result<(), Error> {
-        self.clear_register_bit_flag(Register::SECONDS, BitFlags::CH)
-    }
-
     pub fn get_seconds(&mut self) -> Result<u8, Error> {
         let data = self.read_register(Register::SECONDS)?;
         Ok(packed_bcd_to_decimal(data & !BitFlags::CH))
@@ -112,11 +105,12 @@ where
         ))
     }
 
-    pub fn get_datetime(&mut self) -> Result<DateTime, Error> {
-        Ok(DateTime {
-            date: self.get_date()?,
-            time: self.get_time()?,
-        })
+    fn ram_register(offset: u8, len: usize) -> Result<u8, Error> {
+        let size = (Register::RAM_END - Register::RAM_BEGIN + 1) as usize;
+        if offset as usize + len > size {
+            return Err(Error::RamRange);
+        }
+        Ok(Register::RAM_BEGIN + offset)
     }
 
     fn register_bit_flag_high(&mut self, address: u8, bitmask: u8) -> Result<bool, Error> {
@@ -144,18 +138,77 @@ where
 
     fn write_register(&mut self, register: u8, data: u8) -> Result<(), Error> {
         let payload: [u8; 2] = [register, data];
-        self.i2c.write(ADDR, &payload).map_err(|_| Error::I2C)
+        let result = self.i2c.write(ADDR, &payload);
+        self.check(result)
     }
 
     fn read_register(&mut self, register: u8) -> Result<u8, Error> {
         let mut data = [0];
-        self.i2c
-            .write_read(ADDR, &[register], &mut data)
-            .map_err(|_| Error::I2C)
-            .and(Ok(data[0]))
+        let result = self.i2c.write_read(ADDR, &[register], &mut data);
+        self.check(result).and(Ok(data[0]))
+    }
+
+    /// Counts a failed transfer.
+    fn check<E>(&mut self, result: Result<(), E>) -> Result<(), Error> {
+        result.map_err(|_| {
+            self.errors = self.errors.saturating_add(1);
+            Error::I2C
+        })
     }
 }
 
-fn packed_bcd_to_decimal(bcd: u8) -> u8 {
-    (bcd >> 4) * 10 + (bcd & 0xF)
+impl<I2C> Rtc for Ds1307<I2C>
+where
+    I2C: Write + WriteRead,
+{
+    fn get_datetime(&mut self) -> Result<DateTime, Error> {
+        Ok(DateTime {
+            date: self.get_date()?,
+            time: self.get_time()?,
+        })
+    }
+
+    /// Clears the clock halt flag.
+    fn start(&mut self) -> Result<(), Error> {
+        self.clear_register_bit_flag(Register::SECONDS, BitFlags::CH)
+    }
+
+    fn set_square_wave(&mut self, rate: SquareWave) -> Result<(), Error> {
+        let control = match rate {
+            SquareWave::Hz1 => BitFlags::SQWE,
+            SquareWave::Hz4096 => BitFlags::SQWE | BitFlags::OUTRATERS0,
+            SquareWave::Hz8192 => BitFlags::SQWE | BitFlags::OUTRATERS1,
+            SquareWave::Hz32768 => BitFlags::SQWE | BitFlags::OUTRATERS0 | BitFlags::OUTRATERS1,
+            SquareWave::Level(true) => BitFlags::OUTLEVEL,
+            SquareWave::Level(false) => 0,
+        };
+        self.write_register(Register::SQWOUT, control)
+    }
+
+    fn read_ram(&mut self, offset: u8, data: &mut [u8]) -> Result<(), Error> {
+        let register = Self::ram_register(offset, data.len())?;
+        let result = self.i2c.write_read(ADDR, &[register], data);
+        self.check(result)
+    }
+
+    fn write_ram(&mut self, offset: u8, data: &[u8]) -> Result<(), Error> {
+        let register = Self::ram_register(offset, data.len())?;
+        for (i, b) in data.iter().enumerate() {
+            self.write_register(register + i as u8, *b)?;
+        }
+        Ok(())
+    }
+
+    /// The seven time registers and the square wave control.
+    fn read_registers(&mut self, data: &mut [u8; MAX_REGISTERS]) -> Result<usize, Error> {
+        let len = (Register::SQWOUT + 1) as usize;
+        let result = self
+            .i2c
+            .write_read(ADDR, &[Register::SECONDS], &mut data[..len]);
+        self.check(result).and(Ok(len))
+    }
+
+    fn errors(&self) -> u16 {
+        self.errors
+    }
 }
@@ -0,0 +1,282 @@
+#![allow(dead_code)]
+
+use crate::rtc::{
+    decimal_to_packed_bcd, packed_bcd_to_decimal, Error, Rtc, SquareWave, MAX_REGISTERS,
+};
+use crate::time::{Date, DateTime, Time};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+pub struct Register;
+impl Register {
+    pub const SECONDS: u8 = 0x00;
+    pub const MINUTES: u8 = 0x01;
+    pub const HOURS: u8 = 0x02;
+    pub const DOW: u8 = 0x03;
+    pub const DOM: u8 = 0x04;
+    pub const MONTH: u8 = 0x05;
+    pub const YEAR: u8 = 0x06;
+    pub const ALARM1_SECONDS: u8 = 0x07;
+    pub const ALARM2_MINUTES: u8 = 0x0B;
+    pub const CONTROL: u8 = 0x0E;
+    pub const STATUS: u8 = 0x0F;
+    pub const AGING_OFFSET: u8 = 0x10;
+    pub const TEMP_MSB: u8 = 0x11;
+    pub const TEMP_LSB: u8 = 0x12;
+}
+
+pub struct BitFlags;
+impl BitFlags {
+    pub const H24_H12: u8 = 0b0100_0000;
+    pub const CENTURY: u8 = 0b1000_0000;
+    /// Set in the last register of an alarm, the day or date does not have to match.
+    pub const ALARM_DAILY: u8 = 0b1000_0000;
+    pub const EOSC: u8 = 0b1000_0000;
+    pub const RS2: u8 = 0b0001_0000;
+    pub const RS1: u8 = 0b0000_1000;
+    pub const INTCN: u8 = 0b0000_0100;
+    pub const A2IE: u8 = 0b0000_0010;
+    pub const A1IE: u8 = 0b0000_0001;
+    pub const OSF: u8 = 0b1000_0000;
+    pub const EN32KHZ: u8 = 0b0000_1000;
+    pub const A2F: u8 = 0b0000_0010;
+    pub const A1F: u8 = 0b0000_0001;
+}
+
+/// Same address as the DS1307.
+pub const ADDR: u8 = 0b110_1000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Alarm {
+    /// Matches to the second.
+    One,
+    /// Matches to the minute, the seconds are ignored.
+    Two,
+}
+
+impl Alarm {
+    fn flag(self) -> u8 {
+        match self {
+            Alarm::One => BitFlags::A1F,
+            Alarm::Two => BitFlags::A2F,
+        }
+    }
+
+    fn enable(self) -> u8 {
+        match self {
+            Alarm::One => BitFlags::A1IE,
+            Alarm::Two => BitFlags::A2IE,
+        }
+    }
+}
+
+pub struct Ds3231<I2C> {
+    i2c: I2C,
+    /// Failed I2C transfers.
+    errors: u16,
+}
+
+impl<I2C> Ds3231<I2C>
+where
+    I2C: Write + WriteRead,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c, errors: 0 }
+    }
+
+    pub fn destroy(self) -> I2C {
+        self.i2c
+    }
+
+    pub fn get_time(&mut self) -> Result<Time, Error> {
+        let mut data = [0; 3];
+        self.read_registers_at(Register::SECONDS, &mut data)?;
+        Ok(Time::from_hms(
+            packed_bcd_to_decimal(data[2] & !BitFlags::H24_H12) as u16,
+            packed_bcd_to_decimal(data[1]) as u16,
+            packed_bcd_to_decimal(data[0]) as u16,
+        ))
+    }
+
+    pub fn get_date(&mut self) -> Result<Date, Error> {
+        let mut data = [0; 3];
+        self.read_registers_at(Register::DOM, &mut data)?;
+        Ok(Date::from_ymd(
+            packed_bcd_to_decimal(data[2]) as u16 + 2000,
+            packed_bcd_to_decimal(data[1] & !BitFlags::CENTURY) as u16,
+            packed_bcd_to_decimal(data[0]) as u16,
+        ))
+    }
+
+    /// Whether the oscillator stopped since the flag was cleared, which it does on the
+    /// first power-up and when the battery ran flat. The time is wrong then.
+    pub fn oscillator_stopped(&mut self) -> Result<bool, Error> {
+        Ok(self.read_register(Register::STATUS)? & BitFlags::OSF != 0)
+    }
+
+    /// Clears the oscillator-stop flag, after the time was set.
+    pub fn clear_oscillator_stopped(&mut self) -> Result<(), Error> {
+        self.clear_register_bit_flag(Register::STATUS, BitFlags::OSF)
+    }
+
+    /// Offset added to the capacitance of the crystal, one step is about 0.1 ppm,
+    /// positive values slow the clock down.
+    pub fn aging_offset(&mut self) -> Result<i8, Error> {
+        Ok(self.read_register(Register::AGING_OFFSET)? as i8)
+    }
+
+    /// Takes effect with the next temperature conversion, at the latest after 64 s.
+    pub fn set_aging_offset(&mut self, offset: i8) -> Result<(), Error> {
+        self.write_register(Register::AGING_OFFSET, offset as u8)
+    }
+
+    /// The alarm registers as they are, four of alarm 1 followed by three of alarm 2.
+    pub fn read_alarms(&mut self) -> Result<[u8; 7], Error> {
+        let mut data = [0; 7];
+        self.read_registers_at(Register::ALARM1_SECONDS, &mut data)?;
+        Ok(data)
+    }
+
+    /// Sets `alarm` to go off every day at `time`.
+    pub fn set_daily_alarm(&mut self, alarm: Alarm, time: Time) -> Result<(), Error> {
+        let hour = decimal_to_packed_bcd(time.hour() as u8);
+        let minute = decimal_to_packed_bcd(time.minute() as u8);
+        match alarm {
+            Alarm::One => {
+                let second = decimal_to_packed_bcd(time.second() as u8);
+                let data = [
+                    Register::ALARM1_SECONDS,
+                    second,
+                    minute,
+                    hour,
+                    BitFlags::ALARM_DAILY,
+                ];
+                let result = self.i2c.write(ADDR, &data);
+                self.check(result)
+            }
+            Alarm::Two => {
+                let data = [
+                    Register::ALARM2_MINUTES,
+                    minute,
+                    hour,
+                    BitFlags::ALARM_DAILY,
+                ];
+                let result = self.i2c.write(ADDR, &data);
+                self.check(result)
+            }
+        }
+    }
+
+    /// Lets `alarm` pull the INT/SQW pin low, which ends the square wave.
+    pub fn enable_alarm(&mut self, alarm: Alarm, enable: bool) -> Result<(), Error> {
+        let control = self.read_register(Register::CONTROL)?;
+        let control = if enable {
+            control | BitFlags::INTCN | alarm.enable()
+        } else {
+            control & !alarm.enable()
+        };
+        self.write_register(Register::CONTROL, control)
+    }
+
+    /// Whether `alarm` went off since its flag was cleared.
+    pub fn alarm_fired(&mut self, alarm: Alarm) -> Result<bool, Error> {
+        Ok(self.read_register(Register::STATUS)? & alarm.flag() != 0)
+    }
+
+    /// Clears the flag of `alarm`, which releases the INT/SQW pin.
+    pub fn clear_alarm(&mut self, alarm: Alarm) -> Result<(), Error> {
+        self.clear_register_bit_flag(Register::STATUS, alarm.flag())
+    }
+
+    fn clear_register_bit_flag(&mut self, address: u8, bitmask: u8) -> Result<(), Error> {
+        let data = self.read_register(address)?;
+        if (data & bitmask) != 0 {
+            self.write_register(address, data & !bitmask)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_register(&mut self, register: u8, data: u8) -> Result<(), Error> {
+        let payload: [u8; 2] = [register, data];
+        let result = self.i2c.write(ADDR, &payload);
+        self.check(result)
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error> {
+        let mut data = [0];
+        self.read_registers_at(register, &mut data)?;
+        Ok(data[0])
+    }
+
+    fn read_registers_at(&mut self, register: u8, data: &mut [u8]) -> Result<(), Error> {
+        let result = self.i2c.write_read(ADDR, &[register], data);
+        self.check(result)
+    }
+
+    /// Counts a failed transfer.
+    fn check<E>(&mut self, result: Result<(), E>) -> Result<(), Error> {
+        result.map_err(|_| {
+            self.errors = self.errors.saturating_add(1);
+            Error::I2C
+        })
+    }
+}
+
+impl<I2C> Rtc for Ds3231<I2C>
+where
+    I2C: Write + WriteRead,
+{
+    fn get_datetime(&mut self) -> Result<DateTime, Error> {
+        Ok(DateTime {
+            date: self.get_date()?,
+            time: self.get_time()?,
+        })
+    }
+
+    /// Clears the oscillator disable bit, which only matters on battery.
+    fn start(&mut self) -> Result<(), Error> {
+        self.clear_register_bit_flag(Register::CONTROL, BitFlags::EOSC)
+    }
+
+    /// The DS3231 puts out 32768 Hz on its own 32K pin, not on INT/SQW, and holds
+    /// INT/SQW high while no alarm is enabled.
+    fn set_square_wave(&mut self, rate: SquareWave) -> Result<(), Error> {
+        let control = self.read_register(Register::CONTROL)?
+            & !(BitFlags::RS2 | BitFlags::RS1 | BitFlags::INTCN);
+        let control = match rate {
+            SquareWave::Hz1 => control,
+            SquareWave::Hz4096 => control | BitFlags::RS2,
+            SquareWave::Hz8192 => control | BitFlags::RS2 | BitFlags::RS1,
+            SquareWave::Level(true) => control | BitFlags::INTCN,
+            SquareWave::Hz32768 | SquareWave::Level(false) => return Err(Error::Unsupported),
+        };
+        self.write_register(Register::CONTROL, control)
+    }
+
+    /// The DS3231 has no RAM.
+    fn read_ram(&mut self, _offset: u8, _data: &mut [u8]) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn write_ram(&mut self, _offset: u8, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// All registers, time, alarms, control, status, aging offset and temperature.
+    fn read_registers(&mut self, data: &mut [u8; MAX_REGISTERS]) -> Result<usize, Error> {
+        let len = (Register::TEMP_LSB + 1) as usize;
+        self.read_registers_at(Register::SECONDS, &mut data[..len])?;
+        Ok(len)
+    }
+
+    fn errors(&self) -> u16 {
+        self.errors
+    }
+
+    /// Measured every 64 s by the DS3231 to correct the crystal.
+    fn temperature(&mut self) -> Result<Option<i16>, Error> {
+        let mut data = [0; 2];
+        self.read_registers_at(Register::TEMP_MSB, &mut data)?;
+        Ok(Some(((data[0] as i8 as i16) << 2) | (data[1] >> 6) as i16))
+    }
+}
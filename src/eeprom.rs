@@ -0,0 +1,71 @@
+#![allow(dead_code)]
+
+use arduino_hal::pac::EEPROM;
+
+/// Size of the ATmega328 EEPROM in bytes.
+pub const EEPROM_SIZE: u16 = 1024;
+
+pub enum Error {
+    Range,
+}
+
+/// The internal EEPROM of the ATmega328.
+pub struct Eeprom {
+    eeprom: EEPROM,
+}
+
+impl Eeprom {
+    pub fn new(eeprom: EEPROM) -> Self {
+        Self { eeprom }
+    }
+
+    pub fn read(&self, offset: u16, buf: &mut [u8]) -> Result<(), Error> {
+        Self::check_range(offset, buf.len())?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.read_byte(offset + i as u16);
+        }
+        Ok(())
+    }
+
+    /// Writes `data` starting at `offset`, bytes that already hold their value are skipped
+    /// to spare the EEPROM cells.
+    pub fn write(&mut self, offset: u16, data: &[u8]) -> Result<(), Error> {
+        Self::check_range(offset, data.len())?;
+        for (i, b) in data.iter().enumerate() {
+            let address = offset + i as u16;
+            if self.read_byte(address) != *b {
+                self.write_byte(address, *b);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_range(offset: u16, len: usize) -> Result<(), Error> {
+        if offset as usize + len > EEPROM_SIZE as usize {
+            return Err(Error::Range);
+        }
+        Ok(())
+    }
+
+    fn wait_ready(&self) {
+        while self.eeprom.eecr.read().eepe().bit_is_set() {}
+    }
+
+    fn read_byte(&self, address: u16) -> u8 {
+        self.wait_ready();
+        self.eeprom.eear.write(|w| unsafe { w.bits(address) });
+        self.eeprom.eecr.write(|w| w.eere().set_bit());
+        self.eeprom.eedr.read().bits()
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.wait_ready();
+        self.eeprom.eear.write(|w| unsafe { w.bits(address) });
+        self.eeprom.eedr.write(|w| unsafe { w.bits(value) });
+        // EEPE has to follow EEMPE within four clock cycles
+        avr_device::interrupt::free(|_| {
+            self.eeprom.eecr.write(|w| w.eempe().set_bit());
+            self.eeprom.eecr.write(|w| w.eepe().set_bit());
+        });
+    }
+}
@@ -0,0 +1,253 @@
+#![allow(dead_code)]
+
+use crate::config::{Config, JOB_CLEAN, JOB_FILTER};
+use crate::control::{ControlMode, InterlockViolation, Job, ManualControl, Waterbreach};
+use crate::eeprom::{self, Eeprom};
+use crate::time::DateTime;
+
+/// Region of the EEPROM that holds the log, its upper half.
+const LOG_OFFSET: u16 = 512;
+/// A record is the sequence number, the kind of event, two arguments
+/// and the packed RTC time.
+pub const RECORD_LEN: usize = 8;
+pub const LOG_RECORDS: u16 = 64;
+pub const PAGE_RECORDS: u16 = 8;
+pub const PAGE_LEN: usize = PAGE_RECORDS as usize * RECORD_LEN;
+/// Kind of a slot that was never written.
+const EMPTY: u8 = 0xFF;
+
+/// Something the controller did, as it is recorded in the log.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The controller started, with the reset flags and the resets since power-on.
+    Boot(u8, u8),
+    /// The control mode changed, see `mode_code`.
+    Mode(u8),
+    /// A job started, with its job number and recipe.
+    JobStart(u8, u8),
+    /// A job ended, with its job number and recipe.
+    JobStop(u8, u8),
+    /// Leak zones raised the alarm, with the zones and the action.
+    Breach(u8, u8),
+    BreachCleared,
+    /// Valves got stuck, with the stuck valves.
+    Fault(u8),
+    /// The config was changed and stored.
+    Config,
+    /// The interlocks rejected a valve pattern, with the pattern and the rule it broke.
+    Interlock(u8, u8),
+    /// A job interrupted by a reboot was picked up, with the outcome and the job number.
+    Resume(u8, u8),
+    /// The firmware panicked before the last reset, at this line.
+    Panic(u16),
+    /// The controller reset itself, with the cause: `0` a command, `1` to enter the
+    /// bootloader, `2` the RTC could not be read.
+    Reboot(u8),
+    /// The stack came closer to the static data than `diag::STACK_WARNING`, with the margin.
+    StackLow(u16),
+}
+
+impl Event {
+    fn encode(&self) -> [u8; 3] {
+        match *self {
+            Event::Boot(flags, reboots) => [1, flags, reboots],
+            Event::Mode(mode) => [2, mode, 0],
+            Event::JobStart(job, recipe) => [3, job, recipe],
+            Event::JobStop(job, recipe) => [4, job, recipe],
+            Event::Breach(zones, action) => [5, zones, action],
+            Event::BreachCleared => [6, 0, 0],
+            Event::Fault(stuck) => [7, stuck, 0],
+            Event::Config => [8, 0, 0],
+            Event::Interlock(pattern, rule) => [9, pattern, rule],
+            Event::Resume(outcome, job) => [10, outcome, job],
+            Event::Panic(line) => {
+                let line = line.to_le_bytes();
+                [11, line[0], line[1]]
+            }
+            Event::Reboot(cause) => [12, cause, 0],
+            Event::StackLow(margin) => {
+                let margin = margin.to_le_bytes();
+                [13, margin[0], margin[1]]
+            }
+        }
+    }
+}
+
+/// A ring buffer of events in the EEPROM.
+///
+/// Records are written one slot after the other, so every slot is only written once
+/// per round through the log. The newest record is found again after a reset by its
+/// sequence number.
+pub struct EventLog {
+    next: u16,
+    seq: u8,
+}
+
+impl EventLog {
+    /// Continues the log written before the last reset.
+    pub fn open(eeprom: &Eeprom) -> Result<Self, eeprom::Error> {
+        let mut header = [0; 2];
+        eeprom.read(Self::address(0), &mut header)?;
+        if header[1] == EMPTY {
+            return Ok(Self { next: 0, seq: 0 });
+        }
+        // the records of the current round carry consecutive sequence numbers
+        let mut seq = header[0];
+        let mut newest = 0;
+        for slot in 1..LOG_RECORDS {
+            eeprom.read(Self::address(slot), &mut header)?;
+            if header[1] == EMPTY || header[0] != seq.wrapping_add(1) {
+                break;
+            }
+            seq = header[0];
+            newest = slot;
+        }
+        Ok(Self {
+            next: (newest + 1) % LOG_RECORDS,
+            seq: seq.wrapping_add(1),
+        })
+    }
+
+    pub fn push(&mut self, eeprom: &mut Eeprom, time: DateTime, event: Event) {
+        let [kind, a, b] = event.encode();
+        let time = pack_time(time).to_le_bytes();
+        let record = [self.seq, kind, a, b, time[0], time[1], time[2], time[3]];
+        // the slot always lies within the EEPROM, so the write cannot fail
+        eeprom.write(Self::address(self.next), &record).ok();
+        self.next = (self.next + 1) % LOG_RECORDS;
+        self.seq = self.seq.wrapping_add(1);
+    }
+
+    /// Reads page `page` of the log into `buf`, newest record first.
+    /// Page 0 holds the newest records. Returns the number of bytes read.
+    pub fn read_page(
+        &self,
+        eeprom: &Eeprom,
+        page: u8,
+        buf: &mut [u8; PAGE_LEN],
+    ) -> Result<usize, eeprom::Error> {
+        let mut len = 0;
+        for i in 0..PAGE_RECORDS {
+            let age = page as u16 * PAGE_RECORDS + i;
+            if age >= LOG_RECORDS {
+                break;
+            }
+            let slot = (self.next + LOG_RECORDS - 1 - age) % LOG_RECORDS;
+            let record = &mut buf[len..][..RECORD_LEN];
+            eeprom.read(Self::address(slot), record)?;
+            if record[1] == EMPTY {
+                break;
+            }
+            len += RECORD_LEN;
+        }
+        Ok(len)
+    }
+
+    fn address(slot: u16) -> u16 {
+        LOG_OFFSET + slot * RECORD_LEN as u16
+    }
+}
+
+/// Packs `time` into 32 bits: years since 2000, month, day, hour, minute and second
+/// with 6, 4, 5, 5, 6 and 6 bits.
+fn pack_time(time: DateTime) -> u32 {
+    (time.date.year().saturating_sub(2000) as u32 & 0x3F) << 26
+        | (time.date.month() as u32) << 22
+        | (time.date.day() as u32) << 17
+        | (time.time.hour() as u32) << 12
+        | (time.time.minute() as u32) << 6
+        | time.time.second() as u32
+}
+
+/// The parts of the controller state whose changes are logged.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    mode: u8,
+    job: Option<(u8, u8)>,
+    breach: Option<(u8, u8)>,
+    stuck: u8,
+    config: u16,
+    /// Pattern and rule of the last violation, without its time, so a violation that
+    /// persists is logged once.
+    interlock: Option<(u8, u8)>,
+}
+
+impl Snapshot {
+    pub fn new(
+        mode: &ControlMode,
+        breach: &Waterbreach,
+        stuck: u8,
+        config: &Config,
+        interlock: &InterlockViolation,
+    ) -> Self {
+        let job = match mode {
+            ControlMode::Automatic(job, _)
+            | ControlMode::Manual(ManualControl::CurrentJob(job)) => job_code(job),
+            _ => None,
+        };
+        Self {
+            mode: mode_code(mode),
+            job,
+            breach: if breach.is_latched() {
+                Some((breach.zones(), breach.action as u8))
+            } else {
+                None
+            },
+            stuck,
+            config: config.crc(),
+            interlock: interlock.0.map(|(_, pattern, rule)| (pattern, rule)),
+        }
+    }
+
+    /// Reports the events that lead from `self` to `now`.
+    pub fn changes(&self, now: &Snapshot, mut report: impl FnMut(Event)) {
+        if now.mode != self.mode {
+            report(Event::Mode(now.mode));
+        }
+        if now.job != self.job {
+            if let Some((job, recipe)) = self.job {
+                report(Event::JobStop(job, recipe));
+            }
+            if let Some((job, recipe)) = now.job {
+                report(Event::JobStart(job, recipe));
+            }
+        }
+        if now.breach != self.breach {
+            match now.breach {
+                Some((zones, action)) => report(Event::Breach(zones, action)),
+                None => report(Event::BreachCleared),
+            }
+        }
+        if now.stuck != self.stuck && now.stuck != 0 {
+            report(Event::Fault(now.stuck));
+        }
+        if now.config != self.config {
+            report(Event::Config);
+        }
+        if now.interlock != self.interlock {
+            if let Some((pattern, rule)) = now.interlock {
+                report(Event::Interlock(pattern, rule));
+            }
+        }
+    }
+}
+
+/// Number of a control mode in the log: automatic, manual, breach, fault and off from 0.
+fn mode_code(mode: &ControlMode) -> u8 {
+    match mode {
+        ControlMode::Automatic(_, _) => 0,
+        ControlMode::Manual(_) => 1,
+        ControlMode::Breach => 2,
+        ControlMode::Fault => 3,
+        ControlMode::Off => 4,
+    }
+}
+
+/// Job number and recipe of a running job, idle is no job.
+fn job_code(job: &Job) -> Option<(u8, u8)> {
+    match job {
+        Job::Idle => None,
+        Job::Filter => Some((JOB_FILTER, 0)),
+        Job::Clean(state) => Some((JOB_CLEAN, state.recipe)),
+    }
+}
@@ -0,0 +1,50 @@
+use crate::checksum::checksum;
+use crate::control::{Waterbreach, BREACH_LEN};
+use crate::eeprom::{self, Eeprom};
+
+/// Position of the latched alarms in the EEPROM.
+const EEPROM_OFFSET: u16 = 0;
+const MAGIC: u8 = 0x4C;
+/// Size of the stored alarms: magic, leak alarm, stuck valves and checksum.
+pub const LATCH_LEN: usize = 1 + BREACH_LEN + 1 + 1;
+
+/// Alarms that have to survive a reboot, so the controller does not open
+/// valves into a flooded area or drive a stuck valve after a reset.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Latch {
+    pub breach: [u8; BREACH_LEN],
+    pub stuck: u8,
+}
+
+impl Latch {
+    pub fn new(breach: &Waterbreach, stuck: u8) -> Self {
+        Self {
+            breach: breach.to_bytes(),
+            stuck,
+        }
+    }
+
+    /// Reads the alarms from the EEPROM, `None` if none were stored yet.
+    pub fn load(eeprom: &Eeprom) -> Result<Option<Self>, eeprom::Error> {
+        let mut buf = [0; LATCH_LEN];
+        eeprom.read(EEPROM_OFFSET, &mut buf)?;
+        if buf[0] != MAGIC || buf[LATCH_LEN - 1] != checksum(&buf[..LATCH_LEN - 1]) {
+            return Ok(None);
+        }
+        let mut breach = [0; BREACH_LEN];
+        breach.copy_from_slice(&buf[1..][..BREACH_LEN]);
+        Ok(Some(Self {
+            breach,
+            stuck: buf[1 + BREACH_LEN],
+        }))
+    }
+
+    pub fn store(&self, eeprom: &mut Eeprom) -> Result<(), eeprom::Error> {
+        let mut buf = [0; LATCH_LEN];
+        buf[0] = MAGIC;
+        buf[1..][..BREACH_LEN].copy_from_slice(&self.breach);
+        buf[1 + BREACH_LEN] = self.stuck;
+        buf[LATCH_LEN - 1] = checksum(&buf[..LATCH_LEN - 1]);
+        eeprom.write(EEPROM_OFFSET, &buf)
+    }
+}
@@ -0,0 +1,217 @@
+#![allow(dead_code)]
+
+use crate::config::LeakThresholds;
+use crate::progmem::PmStr;
+use arduino_hal::port::mode::Output;
+use arduino_hal::port::Pin;
+use ufmt::{uDebug, uWrite, uwrite};
+
+/// Leak sensors under the filter housing on A0, in the pump pit on A1
+/// and near the tank inlet on A6.
+pub const ZONE_COUNT: usize = 3;
+
+/// ADC readings averaged into one level.
+pub const SAMPLES: u16 = 8;
+/// Consecutive levels that have to agree before the sensor changes its state.
+const DEBOUNCE: u8 = 2;
+/// Level of an open circuit, the pull-up resistor takes a disconnected input to the supply.
+pub const MISSING: u16 = 1000;
+/// Time the input gets to follow the excitation before it is sampled, in microseconds.
+const EXCITATION_US: u16 = 200;
+const ADC_MAX: u16 = 1023;
+
+/// Name of a leak zone in the status, `None` beyond `ZONE_COUNT`.
+pub fn zone_name(zone: u8) -> Option<PmStr> {
+    match zone {
+        0 => Some(progmem!(b"filter")),
+        1 => Some(progmem!(b"pump_pit")),
+        2 => Some(progmem!(b"inlet")),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LeakState {
+    Dry,
+    Wet,
+    /// The input reads an open circuit, the sensor is not connected.
+    Missing,
+}
+
+impl uDebug for LeakState {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        let name = match self {
+            LeakState::Dry => progmem!(b"dry"),
+            LeakState::Wet => progmem!(b"wet"),
+            LeakState::Missing => progmem!(b"missing"),
+        };
+        uwrite!(f, "{}", name)
+    }
+}
+
+/// Turns averaged ADC levels of a leak sensor into a debounced state.
+pub struct LeakSensor {
+    /// Zone of the sensor, as in `zone_name`.
+    pub zone: u8,
+    pub state: LeakState,
+    /// The last averaged level.
+    pub level: u16,
+    /// Raw readings of a pulsed probe.
+    pub conductance: Option<Conductance>,
+    /// Whether a level above `MISSING` means an open circuit.
+    self_test: bool,
+    /// The other state the last levels pointed to, and how many in a row did.
+    pending: LeakState,
+    pending_count: u8,
+}
+
+impl LeakSensor {
+    /// `self_test` needs the pull-up resistor on the input, a pulsed probe reads an
+    /// open circuit as dry and has to go without.
+    pub const fn new(zone: u8, self_test: bool) -> Self {
+        Self {
+            zone,
+            state: LeakState::Dry,
+            level: 0,
+            conductance: None,
+            self_test,
+            pending: LeakState::Dry,
+            pending_count: 0,
+        }
+    }
+
+    /// Starts the sensor out wet, for a zone whose alarm was kept across a reboot.
+    /// It has to read dry for the full debounce before the alarm can be cleared.
+    pub fn restore_wet(&mut self) {
+        self.state = LeakState::Wet;
+        self.pending_count = 0;
+    }
+
+    /// Takes the next averaged level and returns the debounced state.
+    ///
+    /// The sensor turns wet at the `on` threshold and dry again only below the `off` threshold.
+    pub fn update(&mut self, level: u16, thresholds: &LeakThresholds) -> LeakState {
+        self.level = level;
+        let reading = if self.self_test && level >= MISSING {
+            LeakState::Missing
+        } else if level >= thresholds.on_level() {
+            LeakState::Wet
+        } else if level < thresholds.off_level() {
+            LeakState::Dry
+        } else if self.state == LeakState::Missing {
+            // a sensor that comes back between the thresholds is wet until it reads dry
+            LeakState::Wet
+        } else {
+            self.state
+        };
+        if reading == self.state {
+            self.pending_count = 0;
+        } else {
+            if reading != self.pending {
+                self.pending = reading;
+                self.pending_count = 0;
+            }
+            self.pending_count += 1;
+            if self.pending_count >= DEBOUNCE {
+                self.state = reading;
+                self.pending_count = 0;
+            }
+        }
+        self.state
+    }
+}
+
+impl uDebug for LeakSensor {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, "{}", progmem!(br#"{"zone": ""#))?;
+        if let Some(name) = zone_name(self.zone) {
+            uwrite!(f, "{}", name)?;
+        }
+        uwrite!(f, "{}{:?}", progmem!(br#"", "state": ""#), self.state)?;
+        uwrite!(f, "{}{}", progmem!(br#"", "level": "#), self.level)?;
+        if let Some(conductance) = &self.conductance {
+            uwrite!(f, "{}{:?}", progmem!(br#", "conductance": "#), conductance)?;
+        }
+        f.write_str("}")
+    }
+}
+
+/// Averaged readings of a pulsed probe, one for each polarity.
+#[derive(Clone, Copy)]
+pub struct Conductance {
+    pub forward: u16,
+    pub reverse: Option<u16>,
+}
+
+impl Conductance {
+    /// Level of the probe, higher the more it conducts.
+    pub fn level(&self) -> u16 {
+        match self.reverse {
+            Some(reverse) => (self.forward + (ADC_MAX - reverse.min(ADC_MAX))) / 2,
+            None => self.forward,
+        }
+    }
+}
+
+impl uDebug for Conductance {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        match self.reverse {
+            Some(reverse) => uwrite!(f, "[{}, {}]", self.forward, reverse),
+            None => uwrite!(f, "[{}]", self.forward),
+        }
+    }
+}
+
+/// A leak probe that is only powered while it is measured, so its contacts do not corrode.
+///
+/// One contact sits on `drive`, the other on the analog input, which is held low by a
+/// resistor. With a `sink` pin the resistor goes to `sink` instead of ground and the probe
+/// is measured a second time with reversed polarity.
+pub struct PulsedProbe<PD, PS> {
+    drive: Pin<Output, PD>,
+    sink: Option<Pin<Output, PS>>,
+}
+
+impl<PD, PS> PulsedProbe<PD, PS>
+where
+    PD: avr_hal_generic::port::PinOps,
+    PS: avr_hal_generic::port::PinOps,
+{
+    pub fn new(mut drive: Pin<Output, PD>, mut sink: Option<Pin<Output, PS>>) -> Self {
+        drive.set_low();
+        if let Some(sink) = &mut sink {
+            sink.set_low();
+        }
+        Self { drive, sink }
+    }
+
+    /// Powers the probe, averages the readings taken by `read` and switches it off again.
+    pub fn measure(&mut self, mut read: impl FnMut() -> u16) -> Conductance {
+        self.drive.set_high();
+        arduino_hal::delay_us(EXCITATION_US);
+        let forward = average(&mut read);
+        self.drive.set_low();
+        let reverse = self.sink.as_mut().map(|sink| {
+            sink.set_high();
+            arduino_hal::delay_us(EXCITATION_US);
+            let reverse = average(&mut read);
+            sink.set_low();
+            reverse
+        });
+        Conductance { forward, reverse }
+    }
+}
+
+/// Averages `SAMPLES` readings taken by `read`.
+pub fn average(mut read: impl FnMut() -> u16) -> u16 {
+    (0..SAMPLES).map(|_| read()).sum::<u16>() / SAMPLES
+}
@@ -1,26 +1,117 @@
 #![no_std]
 #![no_main]
+#![feature(abi_avr_interrupt)]
+#![feature(llvm_asm)]
+#![feature(naked_functions)]
 
+use arduino_hal::adc::channel;
 use arduino_hal::hal::wdt;
 use embedded_hal::serial::Read;
 
+// first, so the `progmem!` macro is known to all other modules
+#[macro_use]
+mod progmem;
+
+mod actuator;
+mod checksum;
+mod config;
 mod control;
+mod diag;
 mod ds1307;
+mod ds3231;
+mod eeprom;
+mod events;
+mod latch;
+mod leak;
+mod millis;
+mod power;
+mod protocol;
+mod recipe;
+mod reset;
+mod resume;
+mod rtc;
 mod sr04;
+mod store;
+#[cfg(feature = "sqw-tick")]
+mod tick;
 mod time;
 
+#[cfg(feature = "motor-valves")]
+use actuator::MotorValve;
+#[cfg(not(feature = "pump"))]
+use actuator::NoPump;
+use actuator::{Actuator, Solenoid};
+#[cfg(feature = "valve-feedback")]
+use actuator::{LimitSwitch, Sensed};
+use config::{Config, PumpDelays};
 use control::ControlMode;
 use control::Job;
 use control::ManualControl;
-use control::{Control, VentilGruppe, Waterbreach};
+use control::{Control, InterlockViolation, Tick, ValveMask, VentilGruppe, Waterbreach};
+use diag::{Diagnostics, Info, Stack, STACK_WARNING};
+#[cfg(not(feature = "ds3231"))]
 use ds1307::Ds1307;
+#[cfg(feature = "ds3231")]
+use ds3231::Ds3231;
+use eeprom::Eeprom;
+use events::{Event, EventLog, Snapshot, PAGE_LEN};
+use latch::Latch;
+#[cfg(feature = "leak-excitation")]
+use leak::PulsedProbe;
+use leak::{LeakSensor, LeakState, ZONE_COUNT};
+use power::Power;
+use protocol::{Command, LineBuffer};
+use recipe::{Step, NIGHTLY, RINSE};
+use reset::ResetInfo;
+use resume::{JobRecord, JobStore};
+use rtc::Rtc;
+#[cfg(feature = "sqw-tick")]
+use rtc::SquareWave;
 use sr04::SR04;
-use time::{Date, DateTime, Duration, Time};
+use store::ConfigStore;
+use time::Duration;
+use ufmt::uWrite;
+
+/// Set for relay boards that switch on while their input is low.
+const RELAYS_ACTIVE_LOW: bool = false;
+/// Time the motorized ball valves of the motor-valves feature take from one end to the other.
+#[cfg(feature = "motor-valves")]
+const MOTOR_TRAVEL_MS: u32 = 15_000;
+/// Time the valves get to reach idle before a commanded reboot, in milliseconds.
+const REBOOT_IDLE_MS: u32 = 5000;
+/// Cycles in a row the RTC may fail to be read before the controller resets itself and
+/// sets up the I2C bus and the clock again.
+const RTC_FAILURE_LIMIT: u8 = 5;
+/// Time after the last received byte the CPU stays out of power-down, where the UART stops,
+/// in milliseconds.
+#[cfg(feature = "sqw-tick")]
+const LISTEN_MS: u32 = 10_000;
+/// Time the UART gets to send the last byte of the status before power-down stops it.
+#[cfg(feature = "sqw-tick")]
+const TX_DRAIN_MS: u32 = 2;
+/// A pulsed leak probe reads an open circuit as dry, only the pull-up wiring can tell.
+const LEAK_SELF_TEST: bool = cfg!(not(feature = "leak-excitation"));
+
+#[cfg(all(feature = "motor-valves", feature = "valve-feedback"))]
+compile_error!("motor-valves drives A3 and A2, which valve-feedback reads");
 
 #[arduino_hal::entry]
 fn main() -> ! {
+    // mark the free RAM, the status reports how much of it the stack has ever used
+    diag::paint_stack();
+
     // initialize Peripherals
     let dp = arduino_hal::Peripherals::take().unwrap();
+
+    // a BOOTLOADER command leaves a request behind and has the watchdog reset the controller,
+    // the bootloader is entered before anything is set up
+    let reset_flags = reset::reset_flags(dp.CPU.mcusr.read().bits());
+    if reset::take_bootloader_request(reset_flags) {
+        dp.CPU.mcusr.write(|w| unsafe { w.bits(0) });
+        // SAFETY: MCUSR is cleared and interrupts are still disabled
+        unsafe { reset::jump_to_bootloader() };
+    }
+
     let pins = arduino_hal::pins!(dp);
     let mut serial = arduino_hal::default_serial!(dp, pins, 9600);
     let i2c = arduino_hal::I2c::new(
@@ -30,19 +121,99 @@ fn main() -> ! {
         100,
     );
     let mut adc = arduino_hal::Adc::new(dp.ADC, Default::default());
-    let wasser_pin = pins.a0.into_analog_input(&mut adc);
+    // leak sensors of the zones in leak::zone_name, the third one is on the analog only A6
+    let filter_leak_pin = pins.a0.into_analog_input(&mut adc);
+    let pit_leak_pin = pins.a1.into_analog_input(&mut adc);
+
+    millis::init(dp.TC0);
+    // SAFETY: all state shared with interrupts is behind avr_device::interrupt::Mutex
+    unsafe { avr_device::interrupt::enable() };
+
+    // the reset flags were read before the watchdog driver clears them,
+    // and are cleared here so the next reset only reports its own cause
+    let reset = ResetInfo::take(reset_flags);
 
     // start watchdog
     let mut watchdog = wdt::Wdt::new(dp.WDT, &dp.CPU.mcusr);
     watchdog.start(wdt::Timeout::Ms8000).unwrap();
+    dp.CPU.mcusr.write(|w| unsafe { w.bits(0) });
 
     // initialize sr04 and external clock
     let mut sr04 = SR04::new(dp.TC1, pins.d8.into_output(), pins.d9.forget_imode());
+    // with the ds3231 feature, the DS3231 replaces the DS1307 at the same address
+    #[cfg(feature = "ds3231")]
+    let mut rtc = Ds3231::new(i2c);
+    #[cfg(not(feature = "ds3231"))]
     let mut rtc = Ds1307::new(i2c);
 
     // make sure clock is running
     rtc.start().unwrap_or_else(|_| panic!());
 
+    // with the sqw-tick feature, the 1 Hz square wave of the clock on D2 times the cycles
+    #[cfg(feature = "sqw-tick")]
+    let _sqw_pin = {
+        let pin = pins.d2.into_pull_up_input();
+        rtc.set_square_wave(SquareWave::Hz1)
+            .unwrap_or_else(|_| panic!());
+        tick::init(&dp.EXINT);
+        pin
+    };
+
+    // with the valve-feedback feature, limit switches on A3 and A2 report
+    // the real position of einlass and filterwasser
+    #[cfg(feature = "valve-feedback")]
+    let (einlass, filterwasser) = (
+        Sensed::new(
+            Solenoid::new(pins.d4, RELAYS_ACTIVE_LOW),
+            LimitSwitch::new(pins.a3.into_pull_up_input()),
+        ),
+        Sensed::new(
+            Solenoid::new(pins.d6, RELAYS_ACTIVE_LOW),
+            LimitSwitch::new(pins.a2.into_pull_up_input()),
+        ),
+    );
+    // with the motor-valves feature, einlass and filterwasser are motorized ball valves,
+    // D4 and D6 drive them open, A3 and A2 closed
+    #[cfg(feature = "motor-valves")]
+    let (einlass, filterwasser) = (
+        MotorValve::new(pins.d4, pins.a3, MOTOR_TRAVEL_MS, RELAYS_ACTIVE_LOW),
+        MotorValve::new(pins.d6, pins.a2, MOTOR_TRAVEL_MS, RELAYS_ACTIVE_LOW),
+    );
+    #[cfg(not(any(feature = "valve-feedback", feature = "motor-valves")))]
+    let (einlass, filterwasser) = (
+        Solenoid::new(pins.d4, RELAYS_ACTIVE_LOW),
+        Solenoid::new(pins.d6, RELAYS_ACTIVE_LOW),
+    );
+
+    // with the pump feature, the pump relay is on D3 and the dry-run sensor on D12,
+    // the sensor pulls D12 low while there is water
+    #[cfg(feature = "pump")]
+    let (pump, dry_run_pin) = (
+        Solenoid::new(pins.d3, RELAYS_ACTIVE_LOW),
+        pins.d12.into_pull_up_input(),
+    );
+    #[cfg(not(feature = "pump"))]
+    let pump = NoPump;
+
+    // with the leak-excitation feature, the leak probe is powered from D10 and D11 only while
+    // it is measured, one polarity after the other
+    #[cfg(feature = "leak-excitation")]
+    let mut probe = PulsedProbe::new(pins.d10.into_output(), Some(pins.d11.into_output()));
+
+    // the config lives in the EEPROM, the default config is stored on the first start
+    let mut eeprom = Eeprom::new(dp.EEPROM);
+    let (mut config_store, config) = ConfigStore::load(&eeprom).unwrap_or_else(|_| panic!());
+    let config = match config {
+        Some(config) => config,
+        None => {
+            let config = Config::default();
+            config_store
+                .store(&mut eeprom, &config)
+                .unwrap_or_else(|_| panic!());
+            config
+        }
+    };
+
     // get time and init control struct
     let starttime = rtc.get_datetime().unwrap_or_else(|_| panic!());
 
@@ -50,17 +221,91 @@ fn main() -> ! {
         start_time: starttime,
         current_time: starttime,
         ventil_gruppe: VentilGruppe::new(
-            pins.d4.into_output(),
-            pins.d5.into_output(),
-            pins.d6.into_output(),
-            pins.d7.into_output(),
+            einlass,
+            Solenoid::new(pins.d5, RELAYS_ACTIVE_LOW),
+            filterwasser,
+            Solenoid::new(pins.d7, RELAYS_ACTIVE_LOW),
+            pump,
         ),
         control_mode: ControlMode::Automatic(Job::Idle, Job::Idle),
         already_cleaned: false,
         distance: None,
-        water_breach: Waterbreach(None),
+        water_breach: Waterbreach::new(),
+        leak: [
+            LeakSensor::new(0, LEAK_SELF_TEST),
+            LeakSensor::new(1, LEAK_SELF_TEST),
+            LeakSensor::new(2, LEAK_SELF_TEST),
+        ],
+        interlock: InterlockViolation(None),
+        config,
+        reset,
+        stack: Stack::measure(),
+        power: Power::new(),
+        tick: Tick::Millis,
     };
 
+    // alarms from before a reboot stay in force until they are cleared
+    let mut stored_latch = Latch::load(&eeprom).unwrap_or_else(|_| panic!());
+    if let Some(latch) = stored_latch {
+        if let Some(breach) = Waterbreach::from_bytes(&latch.breach) {
+            // the sensors of the zones in alarm have to read dry through their debounce again
+            for (i, sensor) in control.leak.iter_mut().enumerate() {
+                if breach.zones() & 1 << i != 0 {
+                    sensor.restore_wet();
+                }
+            }
+            control.water_breach = breach;
+        }
+        control.ventil_gruppe.restore_stuck(latch.stuck);
+    }
+
+    let mut log = EventLog::open(&eeprom).unwrap_or_else(|_| panic!());
+    log.push(
+        &mut eeprom,
+        control.current_time,
+        Event::Boot(reset.flags, reset.reboots.min(u8::MAX as u16) as u8),
+    );
+    if let Some(location) = reset.panic {
+        log.push(
+            &mut eeprom,
+            control.current_time,
+            Event::Panic(location.line),
+        );
+    }
+
+    // a job cut short by a power loss carries on, starts over or is dropped,
+    // depending on how long the controller was off, the DS3231 has no RAM and keeps the job
+    // in the EEPROM
+    let (mut job_store, mut stored_job) =
+        JobStore::load(&mut rtc, &eeprom).unwrap_or_else(|_| panic!());
+    if let Some(record) = stored_job {
+        if let Some((mode, outcome)) = record.resume(&control.config.recipes, control.current_time)
+        {
+            control.control_mode = mode;
+            log.push(
+                &mut eeprom,
+                control.current_time,
+                Event::Resume(outcome as u8, record.job()),
+            );
+        }
+    }
+    let mut snapshot = Snapshot::new(
+        &control.control_mode,
+        &control.water_breach,
+        control.ventil_gruppe.stuck(),
+        &control.config,
+        &control.interlock,
+    );
+
+    let mut line = LineBuffer::new();
+    let mut diag = Diagnostics::new();
+    let mut stack_warned = false;
+    let mut rtc_failures = 0;
+    #[cfg(feature = "sqw-tick")]
+    let mut last_rx = 0u32;
+    #[cfg(feature = "sqw-tick")]
+    let mut clock = tick::TickClock::new();
+
     let mut led = pins.d13.into_output();
 
     // main loop
@@ -71,135 +316,595 @@ fn main() -> ! {
             control.distance = sr04.measure_distance();
         }
 
-        control.current_time = rtc.get_datetime().unwrap_or_else(|_| panic!());
+        // with the sqw-tick feature the time is carried on by the ticks and the RTC is read
+        // once a minute, or in every cycle while the square wave is missing
+        #[cfg(feature = "sqw-tick")]
+        let carried = clock.now();
+        #[cfg(not(feature = "sqw-tick"))]
+        let carried: Option<time::DateTime> = None;
 
-        // check for waterbreach
-        if control.water_breach.0.is_none() && wasser_pin.analog_read(&mut adc) > 60 {
-            control.water_breach = Waterbreach(Some(control.current_time));
+        // a failed read keeps the last time, DIAG reports the errors, but the schedule
+        // must not run on a frozen clock for long
+        if let Some(now) = carried {
+            control.current_time = now;
+        } else {
+            match rtc.get_datetime() {
+                Ok(now) => {
+                    control.current_time = now;
+                    rtc_failures = 0;
+                    #[cfg(feature = "sqw-tick")]
+                    clock.set(now);
+                }
+                Err(_) => {
+                    rtc_failures += 1;
+                    if rtc_failures >= RTC_FAILURE_LIMIT {
+                        reboot(
+                            &mut control,
+                            &mut eeprom,
+                            &mut log,
+                            &mut watchdog,
+                            RebootCause::Clock,
+                            || (),
+                        );
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "leak-excitation")]
+        let levels = {
+            let conductances = [
+                probe.measure(|| filter_leak_pin.analog_read(&mut adc)),
+                probe.measure(|| pit_leak_pin.analog_read(&mut adc)),
+                probe.measure(|| adc.read_blocking(&channel::ADC6)),
+            ];
+            let mut levels = [0; ZONE_COUNT];
+            for (i, conductance) in conductances.iter().enumerate() {
+                control.leak[i].conductance = Some(*conductance);
+                levels[i] = conductance.level();
+            }
+            levels
+        };
+        #[cfg(not(feature = "leak-excitation"))]
+        let levels: [u16; ZONE_COUNT] = [
+            leak::average(|| filter_leak_pin.analog_read(&mut adc)),
+            leak::average(|| pit_leak_pin.analog_read(&mut adc)),
+            leak::average(|| adc.read_blocking(&channel::ADC6)),
+        ];
+        diag.adc = [
+            filter_leak_pin.analog_read(&mut adc),
+            pit_leak_pin.analog_read(&mut adc),
+            adc.read_blocking(&channel::ADC6),
+        ];
+        let mut wet = 0;
+        let mut missing = false;
+        for (i, sensor) in control.leak.iter_mut().enumerate() {
+            match sensor.update(levels[i], &control.config.leak[i].thresholds) {
+                LeakState::Wet => wet |= 1 << i,
+                LeakState::Missing => missing = true,
+                LeakState::Dry => (),
+            }
+        }
+
+        // a stuck valve or a missing leak sensor keeps the controller in a safe state
+        // until the fault is cleared
+        if (control.ventil_gruppe.stuck() != 0 || missing)
+            && control.control_mode != ControlMode::Breach
+        {
+            control.control_mode = ControlMode::Fault;
+        }
+
+        // check for waterbreach, depending on the zone the alarm closes the einlass
+        // or holds all valves idle until it is cleared
+        control.water_breach.sense(
+            wet,
+            &control.config.leak,
+            control.current_time,
+            control.control_mode,
+        );
+        if control.water_breach.holds_idle() {
             control.control_mode = ControlMode::Breach;
         }
 
-        if let Ok(b) = serial.read() {
-            match b as char {
-                'a' => control.control_mode = ControlMode::Automatic(Job::Idle, Job::Idle),
-                'b' => {
+        match control.control_mode {
+            ControlMode::Automatic(job, next_job) => {
+                control.set_job_valves(job);
+                // a job only becomes active once its valves are in place
+                if control.ventil_gruppe.is_settled() {
+                    match job {
+                        Job::Idle => {
+                            if control.needs_cleaning(control.current_time.time) {
+                                control.control_mode = ControlMode::Automatic(
+                                    control.start_clean(NIGHTLY).unwrap_or(Job::Idle),
+                                    Job::Idle,
+                                );
+                                control.already_cleaned = true;
+                            } else if let Some(d) = control.distance {
+                                if d > 50 {
+                                    control.control_mode = match control.start_clean(RINSE) {
+                                        Some(clean) => ControlMode::Automatic(clean, Job::Filter),
+                                        None => ControlMode::Automatic(Job::Filter, Job::Idle),
+                                    };
+                                }
+                            }
+                        }
+                        Job::Filter => {
+                            if control.distance.filter(|d| *d >= 10).is_none() {
+                                control.control_mode = ControlMode::Automatic(
+                                    control.start_clean(RINSE).unwrap_or(Job::Idle),
+                                    Job::Idle,
+                                );
+                            }
+                        }
+                        Job::Clean(state) => {
+                            if state.is_finished(control.current_time) {
+                                control.control_mode = match state
+                                    .next_step(&control.config.recipes, control.current_time)
+                                {
+                                    Some(state) => {
+                                        ControlMode::Automatic(Job::Clean(state), next_job)
+                                    }
+                                    None => ControlMode::Automatic(next_job, Job::Idle),
+                                };
+                            }
+                        }
+                    }
+                } else if let Job::Clean(state) = job {
+                    let state = state.restart(&control.config.recipes, control.current_time);
+                    control.control_mode = ControlMode::Automatic(Job::Clean(state), next_job);
+                }
+            }
+            ControlMode::Manual(manualcontrol) => match manualcontrol {
+                ManualControl::CurrentJob(job) => {
+                    control.set_job_valves(job);
+                    if let Job::Clean(state) = job {
+                        let job = if !control.ventil_gruppe.is_settled() {
+                            Job::Clean(state.restart(&control.config.recipes, control.current_time))
+                        } else if state.is_finished(control.current_time) {
+                            state
+                                .next_step(&control.config.recipes, control.current_time)
+                                .map_or(Job::Idle, Job::Clean)
+                        } else {
+                            job
+                        };
+                        control.control_mode = ControlMode::Manual(ManualControl::CurrentJob(job));
+                    }
+                }
+                ManualControl::Bridged(pattern) => {
+                    control.set_valves(pattern).ok();
+                }
+            },
+            ControlMode::Breach => control.set_job_valves(Job::Idle),
+            ControlMode::Fault => control.ventil_gruppe.close_all(),
+            ControlMode::Off => {
+                control.set_job_valves(Job::Idle);
+            }
+        }
+
+        // record what changed since the last cycle, including changes made by commands
+        let now = Snapshot::new(
+            &control.control_mode,
+            &control.water_breach,
+            control.ventil_gruppe.stuck(),
+            &control.config,
+            &control.interlock,
+        );
+        snapshot.changes(&now, |event| {
+            log.push(&mut eeprom, control.current_time, event)
+        });
+        snapshot = now;
+
+        let latch = Latch::new(&control.water_breach, control.ventil_gruppe.stuck());
+        if stored_latch != Some(latch) {
+            latch.store(&mut eeprom).unwrap_or_else(|_| panic!());
+            stored_latch = Some(latch);
+        }
+
+        let record = JobRecord::new(&control.control_mode, control.current_time);
+        if record.is_due(&stored_job, job_store.heartbeat())
+            && job_store.store(&record, &mut rtc, &mut eeprom).is_ok()
+        {
+            stored_job = Some(record);
+        }
+
+        // the margin only shrinks, so the warning is logged once
+        control.stack = Stack::measure();
+        if control.stack.margin < STACK_WARNING && !stack_warned {
+            stack_warned = true;
+            log.push(
+                &mut eeprom,
+                control.current_time,
+                Event::StackLow(control.stack.margin),
+            );
+        }
+
+        diag.echo = sr04.echo_count();
+        if let Ok(len) = rtc.read_registers(&mut diag.rtc) {
+            diag.rtc_len = len;
+        }
+        if let Ok(temperature) = rtc.temperature() {
+            diag.rtc_temperature = temperature;
+        }
+        diag.i2c_errors = rtc.errors();
+
+        control.power.end_cycle();
+        control.tick = CycleStart::tick();
+        ufmt::uwriteln!(&mut serial, "{:?}!!", control).unwrap();
+
+        watchdog.feed();
+
+        // wait for the next cycle, switching valves and handling commands as they come in
+        let cycle_start = CycleStart::now();
+        while cycle_start.elapsed() < 4000 {
+            #[cfg(feature = "pump")]
+            control.ventil_gruppe.set_dry_run(dry_run_pin.is_high());
+            control.poll_valves(millis::millis());
+            #[cfg(feature = "sqw-tick")]
+            if tick::take_rx_wake() {
+                last_rx = millis::millis();
+            }
+            while let Ok(b) = serial.read() {
+                #[cfg(feature = "sqw-tick")]
+                {
+                    last_rx = millis::millis();
+                }
+                match line.push(b) {
+                    Some(Ok(command)) => handle_command(
+                        command,
+                        &mut control,
+                        &mut eeprom,
+                        &mut config_store,
+                        &mut log,
+                        &mut watchdog,
+                        &diag,
+                        &mut serial,
+                    ),
+                    Some(Err(e)) => ufmt::uwriteln!(&mut serial, "ERR {:?}", e).unwrap(),
+                    None => (),
+                }
+            }
+            // with nothing timed in milliseconds and no command coming in, only the next tick
+            // of the clock or a start bit on RXD wakes the CPU
+            #[cfg(feature = "sqw-tick")]
+            if tick::is_running()
+                && control.ventil_gruppe.is_at_rest()
+                && line.is_empty()
+                && millis::millis().wrapping_sub(last_rx) >= LISTEN_MS
+                && millis::millis().wrapping_sub(cycle_start.millis) >= TX_DRAIN_MS
+            {
+                power::power_down();
+                continue;
+            }
+            // the millisecond tick wakes the CPU again, in time for the next received byte
+            power::idle();
+        }
+    }
+}
+
+fn handle_command<A1, A2, A3, A4, PU, W>(
+    command: Command,
+    control: &mut Control<A1, A2, A3, A4, PU>,
+    eeprom: &mut Eeprom,
+    config_store: &mut ConfigStore,
+    log: &mut EventLog,
+    watchdog: &mut wdt::Wdt,
+    diag: &Diagnostics,
+    serial: &mut W,
+) where
+    A1: Actuator,
+    A2: Actuator,
+    A3: Actuator,
+    A4: Actuator,
+    PU: Actuator,
+    W: uWrite,
+{
+    let result = match command {
+        Command::Legacy(b) => {
+            match b {
+                // the breach mode only ends with CLEAR
+                b'a' if control.water_breach.holds_idle() => (),
+                b'a' => control.control_mode = ControlMode::Automatic(Job::Idle, Job::Idle),
+                b'b' => {
                     control.control_mode = ControlMode::Manual(ManualControl::CurrentJob(Job::Idle))
                 }
-                'c' => {
+                b'c' => {
                     control.control_mode =
                         ControlMode::Manual(ManualControl::CurrentJob(Job::Filter))
                 }
-                'd' => {
+                b'd' => {
                     control.control_mode = ControlMode::Manual(ManualControl::CurrentJob(
-                        Job::Clean(Date::from_ymd(0, 0, 0).with_hms(0, 0, 0)),
+                        control.start_clean(NIGHTLY).unwrap_or(Job::Idle),
                     ))
                 }
-                '1' => {
+                b'1' => {
                     control.control_mode =
-                        ControlMode::Manual(ManualControl::Bridged(true, false, false, false))
+                        ControlMode::Manual(ManualControl::Bridged(ValveMask::EINLASS))
                 }
-                '2' => {
+                b'2' => {
                     control.control_mode =
-                        ControlMode::Manual(ManualControl::Bridged(false, true, false, false))
+                        ControlMode::Manual(ManualControl::Bridged(ValveMask::ABWASSER))
                 }
-                '3' => {
+                b'3' => {
                     control.control_mode =
-                        ControlMode::Manual(ManualControl::Bridged(false, false, true, false))
+                        ControlMode::Manual(ManualControl::Bridged(ValveMask::FILTERWASSER))
                 }
-                '4' => {
+                b'4' => {
                     control.control_mode =
-                        ControlMode::Manual(ManualControl::Bridged(false, false, false, true))
+                        ControlMode::Manual(ManualControl::Bridged(ValveMask::BRIDGE))
                 }
-                'o' => control.control_mode = ControlMode::Off,
-                'r' => control.water_breach = Waterbreach(None),
-                'p' => panic!(),
+                b'o' => control.control_mode = ControlMode::Off,
+                b'r' => control.water_breach.acknowledge(),
+                b'p' => reboot(control, eeprom, log, watchdog, RebootCause::Command, || ()),
                 _ => (),
             }
+            // the app does not expect an answer to its one byte commands
+            return;
         }
-
-        match control.control_mode {
-            ControlMode::Automatic(ref job, ref next_job) => match (job, next_job) {
-                (Job::Idle, _) => {
-                    control.ventil_gruppe.set_idle();
-                    if control.needs_cleaning(control.current_time.time) {
-                        control.control_mode = ControlMode::Automatic(
-                            Job::Clean(control.current_time.add_duration(Duration(10))),
-                            Job::Idle,
-                        );
-                        control.already_cleaned = true;
-                    } else if let Some(d) = control.distance {
-                        if d > 50 {
-                            control.control_mode = ControlMode::Automatic(
-                                Job::Clean(control.current_time.add_duration(Duration(5))),
-                                Job::Filter,
-                            );
-                        }
-                    }
-                }
-                (Job::Filter, _) => {
-                    control.ventil_gruppe.set_filter();
-                    if control.distance.filter(|d| *d >= 10).is_none() {
-                        control.control_mode = ControlMode::Automatic(
-                            Job::Clean(control.current_time.add_duration(Duration(5))),
-                            Job::Idle,
-                        );
-                    }
-                }
-                (Job::Clean(stoptime), Job::Idle) => {
-                    control.ventil_gruppe.set_clean();
-                    if control.current_time.ge(stoptime) {
-                        control.control_mode = ControlMode::Automatic(Job::Idle, Job::Idle);
-                    }
-                }
-                (Job::Clean(stoptime), Job::Filter) => {
-                    control.ventil_gruppe.set_clean();
-                    if control.current_time.ge(stoptime) {
-                        control.control_mode = ControlMode::Automatic(Job::Filter, Job::Idle);
-                    }
-                }
-                _ => {
-                    control.ventil_gruppe.set_idle();
-                }
-            },
-            ControlMode::Manual(ref manualcontrol) => match manualcontrol {
-                ManualControl::CurrentJob(job) => match job {
-                    Job::Idle => {
-                        control.ventil_gruppe.set_idle();
-                    }
-                    Job::Filter => {
-                        control.ventil_gruppe.set_filter();
-                    }
-                    Job::Clean(_) => {
-                        control.ventil_gruppe.set_clean();
-                    }
-                },
-                ManualControl::Bridged(v1, v2, v3, v4) => {
-                    control.ventil_gruppe.einlass.set(*v1);
-                    control.ventil_gruppe.abwasser.set(*v2);
-                    control.ventil_gruppe.filterwasser.set(*v3);
-                    control.ventil_gruppe.bridge.set(*v4);
+        Command::Recipe(r) => match control.config.recipes.get(r as usize) {
+            Some(recipe) => {
+                ufmt::uwriteln!(serial, "{:?}", recipe).ok();
+                return;
+            }
+            None => Err(protocol::Error::Range),
+        },
+        Command::Step(r, i, pattern, seconds) => control
+            .config
+            .set_step(r, i, Step::new(pattern, Duration(seconds)))
+            .map_err(protocol::Error::from)
+            .and_then(|_| store_config(&control.config, config_store, eeprom)),
+        Command::Steps(r, len) => match control.config.recipes.get_mut(r as usize) {
+            Some(recipe) => recipe
+                .truncate(len)
+                .map_err(protocol::Error::from)
+                .and_then(|_| store_config(&control.config, config_store, eeprom)),
+            None => Err(protocol::Error::Range),
+        },
+        Command::Valves => {
+            ufmt::uwriteln!(serial, "{:?}", control.config.valves).ok();
+            return;
+        }
+        Command::Manual(pattern) if !ValveMask::is_valid(pattern) => Err(protocol::Error::Invalid),
+        Command::Manual(pattern) => match control.check_valves(pattern) {
+            Ok(()) => {
+                control.control_mode = ControlMode::Manual(ManualControl::Bridged(pattern));
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        },
+        Command::Interlocks => {
+            ufmt::uwriteln!(serial, "{:?}", control.config.interlocks).ok();
+            return;
+        }
+        Command::SetInterlock(index, rule) => {
+            let manual = match control.control_mode {
+                ControlMode::Manual(ManualControl::Bridged(pattern)) => Some(pattern),
+                _ => None,
+            };
+            control
+                .config
+                .set_interlock(index, rule, manual)
+                .map_err(protocol::Error::from)
+                .and_then(|_| store_config(&control.config, config_store, eeprom))
+        }
+        Command::Acknowledge => {
+            control.water_breach.acknowledge();
+            Ok(())
+        }
+        Command::Clear => {
+            let result = control.clear_breach().map_err(protocol::Error::from);
+            control.ventil_gruppe.clear_stuck();
+            if control.control_mode == ControlMode::Fault {
+                control.control_mode = ControlMode::Off;
+            }
+            result
+        }
+        Command::StuckTimeout => {
+            ufmt::uwriteln!(serial, "{}", control.config.stuck_timeout).ok();
+            return;
+        }
+        Command::SetStuckTimeout(seconds) => control
+            .config
+            .set_stuck_timeout(seconds)
+            .map_err(protocol::Error::from)
+            .and_then(|_| store_config(&control.config, config_store, eeprom)),
+        Command::Pump => {
+            ufmt::uwriteln!(serial, "{:?}", control.config.pump).ok();
+            return;
+        }
+        Command::SetPump(on, off) => {
+            control.config.pump = PumpDelays { on, off };
+            store_config(&control.config, config_store, eeprom)
+        }
+        Command::Log(page) => {
+            let mut buf = [0; PAGE_LEN];
+            match log.read_page(eeprom, page, &mut buf) {
+                Ok(len) => {
+                    write_hex(serial, &buf[..len]).ok();
+                    ufmt::uwriteln!(serial, "").ok();
+                    return;
                 }
-            },
-            ControlMode::Breach => control.ventil_gruppe.set_idle(),
-            ControlMode::Off => {
-                control.ventil_gruppe.set_idle();
+                Err(_) => Err(protocol::Error::Storage),
             }
         }
+        Command::FactoryReset => {
+            control.config = Config::default();
+            store_config(&control.config, config_store, eeprom)
+        }
+        Command::Leak => {
+            ufmt::uwriteln!(serial, "{:?}", control.config.leak).ok();
+            return;
+        }
+        Command::SetLeak(zone, on, off, action) => match control.config.leak.get_mut(zone as usize)
+        {
+            Some(leak_zone) => leak_zone
+                .set(on, off, action)
+                .map_err(protocol::Error::from)
+                .and_then(|_| store_config(&control.config, config_store, eeprom)),
+            None => Err(protocol::Error::Range),
+        },
+        Command::Settle => {
+            ufmt::uwriteln!(serial, "{:?}", control.config.settle).ok();
+            return;
+        }
+        Command::SetSettle(valve, tenths) => control
+            .config
+            .settle
+            .set(valve, tenths)
+            .map_err(protocol::Error::from)
+            .and_then(|_| store_config(&control.config, config_store, eeprom)),
+        Command::SetValves(job, pattern) => control
+            .config
+            .set_valves(job, pattern)
+            .map_err(protocol::Error::from)
+            .and_then(|_| store_config(&control.config, config_store, eeprom)),
+        Command::Info => {
+            ufmt::uwriteln!(serial, "{:?}", Info).ok();
+            return;
+        }
+        Command::Diag => {
+            ufmt::uwriteln!(serial, "{:?}", diag).ok();
+            return;
+        }
+        Command::Reboot => reboot(control, eeprom, log, watchdog, RebootCause::Command, || {
+            ufmt::uwriteln!(serial, "OK").ok();
+        }),
+        Command::Bootloader => match reset::bootloader_version() {
+            Some(_) => reboot(
+                control,
+                eeprom,
+                log,
+                watchdog,
+                RebootCause::Bootloader,
+                || {
+                    ufmt::uwriteln!(serial, "OK").ok();
+                },
+            ),
+            None => Err(protocol::Error::Unsupported),
+        },
+    };
+    match result {
+        Ok(()) => ufmt::uwriteln!(serial, "OK").ok(),
+        Err(e) => ufmt::uwriteln!(serial, "ERR {:?}", e).ok(),
+    };
+}
+
+/// When the wait for the next cycle started, in ticks of the clock and in milliseconds.
+#[derive(Clone, Copy)]
+struct CycleStart {
+    #[cfg(feature = "sqw-tick")]
+    ticks: u32,
+    millis: u32,
+}
+
+impl CycleStart {
+    fn now() -> Self {
+        Self {
+            #[cfg(feature = "sqw-tick")]
+            ticks: tick::ticks(),
+            millis: millis::millis(),
+        }
+    }
 
-        ufmt::uwriteln!(&mut serial, "{:?}!!", control).unwrap();
+    /// Milliseconds since the start. With the sqw-tick feature they are counted in whole
+    /// seconds by the clock, so the cycles keep step with it, and by Timer0 while the
+    /// square wave is missing.
+    fn elapsed(&self) -> u32 {
+        #[cfg(feature = "sqw-tick")]
+        if tick::is_running() {
+            return tick::ticks().wrapping_sub(self.ticks).wrapping_mul(1000);
+        }
+        millis::millis().wrapping_sub(self.millis)
+    }
 
+    /// What paces the cycles right now.
+    fn tick() -> Tick {
+        #[cfg(feature = "sqw-tick")]
+        if tick::is_running() {
+            return Tick::Sqw;
+        }
+        Tick::Millis
+    }
+}
+
+/// Why the controller resets itself, logged with the reboot.
+#[derive(Clone, Copy, PartialEq)]
+enum RebootCause {
+    Command = 0,
+    /// The controller starts into the bootloader.
+    Bootloader = 1,
+    /// The RTC could not be read for `RTC_FAILURE_LIMIT` cycles in a row.
+    Clock = 2,
+}
+
+/// Moves the valves to idle, logs the reboot and has the watchdog reset the controller.
+///
+/// `announce` runs once the valves are idle, right before the reset. The `OK` to a text
+/// command is sent from there, because Optiboot waits for the host for about a second only.
+fn reboot<A1, A2, A3, A4, PU>(
+    control: &mut Control<A1, A2, A3, A4, PU>,
+    eeprom: &mut Eeprom,
+    log: &mut EventLog,
+    watchdog: &mut wdt::Wdt,
+    cause: RebootCause,
+    announce: impl FnOnce(),
+) -> !
+where
+    A1: Actuator,
+    A2: Actuator,
+    A3: Actuator,
+    A4: Actuator,
+    PU: Actuator,
+{
+    control.control_mode = ControlMode::Off;
+    control.set_job_valves(Job::Idle);
+    let start = millis::millis();
+    while !control.ventil_gruppe.is_settled()
+        && millis::millis().wrapping_sub(start) < REBOOT_IDLE_MS
+    {
+        control.poll_valves(millis::millis());
         watchdog.feed();
-        arduino_hal::delay_ms(4000);
+    }
+    log.push(eeprom, control.current_time, Event::Reboot(cause as u8));
+    if cause == RebootCause::Bootloader {
+        reset::request_bootloader();
+    }
+    announce();
+    watchdog.start(wdt::Timeout::Ms16).unwrap();
+    loop {
+        avr_device::asm::nop();
     }
 }
 
+fn store_config(
+    config: &Config,
+    config_store: &mut ConfigStore,
+    eeprom: &mut Eeprom,
+) -> Result<(), protocol::Error> {
+    config_store
+        .store(eeprom, config)
+        .map_err(|_| protocol::Error::Storage)
+}
+
+/// Writes `data` as lowercase hex digits.
+fn write_hex<W: uWrite>(serial: &mut W, data: &[u8]) -> Result<(), W::Error> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for b in data {
+        serial.write_char(DIGITS[(b >> 4) as usize] as char)?;
+        serial.write_char(DIGITS[(b & 0x0F) as usize] as char)?;
+    }
+    Ok(())
+}
+
 // the watchdog should restart the device afer a panic
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     // disable interrupts - firmware has panicked so no ISRs should continue running
     avr_device::interrupt::disable();
 
-    // get the peripherals so we can access the LED.
+    // keep the location for the status after the watchdog reset
+    reset::record_panic(info.location());
+
+    // get the peripherals so we can access the valves, the UART and the LED.
     //
     // SAFETY: Because main() already has references to the peripherals this is an unsafe
     // operation - but because no other code can run after the panic handler was called,
@@ -207,7 +912,37 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     let dp = unsafe { arduino_hal::Peripherals::steal() };
     let pins = arduino_hal::pins!(dp);
 
-    // Blink LED rapidly
+    // close every valve and stop the pump, a relay left on would keep its valve open
+    // until the watchdog resets the controller
+    Solenoid::new(pins.d4, RELAYS_ACTIVE_LOW);
+    Solenoid::new(pins.d5, RELAYS_ACTIVE_LOW);
+    Solenoid::new(pins.d6, RELAYS_ACTIVE_LOW);
+    Solenoid::new(pins.d7, RELAYS_ACTIVE_LOW);
+    #[cfg(feature = "pump")]
+    Solenoid::new(pins.d3, RELAYS_ACTIVE_LOW);
+    // a motor stops where it is, it is driven closed again after the reset
+    #[cfg(feature = "motor-valves")]
+    {
+        Solenoid::new(pins.a3, RELAYS_ACTIVE_LOW);
+        Solenoid::new(pins.a2, RELAYS_ACTIVE_LOW);
+    }
+    #[cfg(feature = "leak-excitation")]
+    PulsedProbe::new(pins.d10.into_output(), Some(pins.d11.into_output()));
+
+    let mut serial = arduino_hal::default_serial!(dp, pins, 9600);
+    match info.location() {
+        Some(location) => ufmt::uwriteln!(
+            &mut serial,
+            "PANIC {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        ),
+        None => ufmt::uwriteln!(&mut serial, "PANIC"),
+    }
+    .ok();
+
+    // Blink LED rapidly until the watchdog resets the controller
     let mut led = pins.d13.into_output();
     loop {
         led.toggle();
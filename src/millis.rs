@@ -0,0 +1,51 @@
+use arduino_hal::pac::TC0;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+// Timer0 runs at 16 MHz / 64 and matches every 250 counts, i.e. once per millisecond.
+const PRESCALER: u32 = 64;
+const TIMER_COUNTS: u32 = 250;
+const MILLIS_INCREMENT: u32 = PRESCALER * TIMER_COUNTS / 16000;
+
+static MILLIS_COUNTER: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Starts counting milliseconds on Timer0. Interrupts have to be enabled afterwards.
+pub fn init(tc0: TC0) {
+    tc0.tccr0a.write(|w| w.wgm0().ctc());
+    tc0.ocr0a
+        .write(|w| unsafe { w.bits((TIMER_COUNTS - 1) as u8) });
+    tc0.tccr0b.write(|w| w.cs0().prescale_64());
+    tc0.timsk0.write(|w| w.ocie0a().set_bit());
+
+    avr_device::interrupt::free(|cs| MILLIS_COUNTER.borrow(cs).set(0));
+}
+
+/// Milliseconds since `init`, wraps after about 49 days.
+pub fn millis() -> u32 {
+    avr_device::interrupt::free(|cs| MILLIS_COUNTER.borrow(cs).get())
+}
+
+/// Microseconds since `init` in steps of 4, wraps after about 71 minutes.
+pub fn micros() -> u32 {
+    avr_device::interrupt::free(|cs| {
+        // SAFETY: only reads Timer0, which `init` set up
+        let tc0 = unsafe { &*TC0::ptr() };
+        let mut millis = MILLIS_COUNTER.borrow(cs).get();
+        let counts = tc0.tcnt0.read().bits() as u32;
+        // a match that is still pending has not been counted yet
+        if tc0.tifr0.read().ocf0a().bit_is_set() && counts < TIMER_COUNTS - 1 {
+            millis = millis.wrapping_add(MILLIS_INCREMENT);
+        }
+        millis
+            .wrapping_mul(1000)
+            .wrapping_add(counts * PRESCALER / 16)
+    })
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn TIMER0_COMPA() {
+    avr_device::interrupt::free(|cs| {
+        let counter = MILLIS_COUNTER.borrow(cs);
+        counter.set(counter.get().wrapping_add(MILLIS_INCREMENT));
+    })
+}
@@ -0,0 +1,133 @@
+use crate::millis;
+#[cfg(feature = "sqw-tick")]
+use crate::tick;
+use arduino_hal::pac::CPU;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+use ufmt::{uDebug, uWrite, uwrite};
+
+/// Typical supply current of the ATmega328 at 16 MHz and 5 V from the datasheet, while it
+/// runs and while it sleeps in idle mode, in µA. Nothing on the board measures the current.
+const ACTIVE_UA: u32 = 9_500;
+const IDLE_UA: u32 = 2_800;
+/// Current of the ATmega328 in power-down with the watchdog and brown-out detector on, in µA.
+const POWER_DOWN_UA: u32 = 30;
+/// Current of the rest of the board, regulator, USB bridge, LEDs and sensors, in µA.
+/// Measure it once on the board at hand, the default fits a Nano with an HC-05.
+const BOARD_UA: u32 = 45_000;
+
+/// Microseconds spent in `idle` since the cycle started.
+static ASLEEP: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Sleeps in idle mode until the next interrupt, at the latest the next millisecond tick.
+///
+/// Timers, the UART and the ADC keep running in idle mode, only the CPU stops.
+/// Interrupts have to be enabled, or the CPU never wakes up.
+pub fn idle() {
+    // SAFETY: SMCR is only written here and in `power_down`
+    let cpu = unsafe { &*CPU::ptr() };
+    let before = millis::micros();
+    cpu.smcr.write(|w| w.sm().idle().se().set_bit());
+    avr_device::asm::sleep();
+    cpu.smcr.write(|w| w.se().clear_bit());
+    let asleep = millis::micros().wrapping_sub(before);
+    avr_device::interrupt::free(|cs| {
+        let total = ASLEEP.borrow(cs);
+        total.set(total.get().wrapping_add(asleep));
+    });
+}
+
+/// Sleeps in power-down until the next tick of the clock or a start bit on RXD.
+///
+/// Timer0 and the UART stop, so `millis` stands still and the byte that wakes the CPU is
+/// lost. Only call it while nothing is timed in milliseconds and no command is expected.
+/// The watchdog keeps running and resets the controller if the ticks stop.
+#[cfg(feature = "sqw-tick")]
+pub fn power_down() {
+    // SAFETY: SMCR is only written here and in `idle`
+    let cpu = unsafe { &*CPU::ptr() };
+    tick::watch_rxd(true);
+    cpu.smcr.write(|w| w.sm().pdown().se().set_bit());
+    avr_device::asm::sleep();
+    cpu.smcr.write(|w| w.se().clear_bit());
+    tick::watch_rxd(false);
+}
+
+/// How much of the last cycle the CPU was awake or in power-down, and an estimate of what
+/// the board drew on average.
+#[derive(Clone, Copy)]
+pub struct Power {
+    cycle_start: u32,
+    #[cfg(feature = "sqw-tick")]
+    tick_start: u32,
+    /// Per mille of the last cycle the CPU was awake.
+    awake: u16,
+    /// Per mille of the last cycle the CPU spent in power-down, the rest it was idle.
+    power_down: u16,
+}
+
+impl Power {
+    pub fn new() -> Self {
+        Self {
+            cycle_start: millis::micros(),
+            #[cfg(feature = "sqw-tick")]
+            tick_start: tick::ticks(),
+            awake: 1000,
+            power_down: 0,
+        }
+    }
+
+    /// Ends the measurement of a cycle and starts the next one.
+    pub fn end_cycle(&mut self) {
+        let now = millis::micros();
+        // Timer0 stops in power-down, the time it did not count went by in power-down
+        let counted = now.wrapping_sub(self.cycle_start) / 1000;
+        #[cfg(feature = "sqw-tick")]
+        let length = {
+            let ticks = tick::ticks();
+            let length = ticks.wrapping_sub(self.tick_start).saturating_mul(1000);
+            self.tick_start = ticks;
+            length.max(counted)
+        };
+        #[cfg(not(feature = "sqw-tick"))]
+        let length = counted;
+        let asleep = avr_device::interrupt::free(|cs| ASLEEP.borrow(cs).replace(0)) / 1000;
+        if length > 0 {
+            let awake = counted - asleep.min(counted);
+            self.awake = (awake * 1000 / length) as u16;
+            self.power_down = ((length - counted) * 1000 / length) as u16;
+        }
+        self.cycle_start = now;
+    }
+
+    /// Average supply current in µA, estimated from the time spent in each sleep mode.
+    pub fn estimated_current(&self) -> u32 {
+        let awake = self.awake as u32;
+        let power_down = self.power_down as u32;
+        let idle = 1000 - awake - power_down;
+        BOARD_UA + (ACTIVE_UA * awake + IDLE_UA * idle + POWER_DOWN_UA * power_down) / 1000
+    }
+}
+
+impl uDebug for Power {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        let sleep = if self.power_down > 0 {
+            progmem!(b"power_down")
+        } else {
+            progmem!(b"idle")
+        };
+        uwrite!(f, "{}{}", progmem!(br#"{"sleep": ""#), sleep)?;
+        uwrite!(f, "{}{}", progmem!(br#"", "awake": "#), self.awake)?;
+        uwrite!(f, "{}{}", progmem!(br#", "power_down": "#), self.power_down)?;
+        uwrite!(
+            f,
+            "{}{}",
+            progmem!(br#", "estimated_ua": "#),
+            self.estimated_current()
+        )?;
+        f.write_str("}")
+    }
+}
@@ -0,0 +1,92 @@
+use ufmt::{uDisplay, uWrite};
+
+/// Bytes copied out of the flash at a time while a text is written.
+const CHUNK: usize = 16;
+
+/// Keeps an ASCII byte string in program memory instead of RAM and evaluates to a `PmStr`
+/// that writes it with `{}`. The text is a byte string literal or a constant `&[u8]`,
+/// like `env!("GIT_HASH").as_bytes()`.
+macro_rules! progmem {
+    ($text:expr) => {{
+        const BYTES: &[u8] = $text;
+        const _: [(); 1] = [(); $crate::progmem::is_ascii(BYTES) as usize];
+        #[link_section = ".progmem.data"]
+        static TEXT: [u8; BYTES.len()] = $crate::progmem::to_array(BYTES);
+        // SAFETY: the text is ASCII and placed in program memory
+        unsafe { $crate::progmem::PmStr::new(&TEXT) }
+    }};
+}
+
+/// A text in program memory, which the CPU can only read with `lpm`.
+#[derive(Clone, Copy)]
+pub struct PmStr {
+    address: *const u8,
+    len: usize,
+}
+
+impl PmStr {
+    /// Only for `progmem!`.
+    ///
+    /// # Safety
+    ///
+    /// `text` has to be ASCII and placed in program memory, otherwise `lpm` reads
+    /// whatever lies in the flash at its RAM address.
+    #[doc(hidden)]
+    pub const unsafe fn new(text: &'static [u8]) -> Self {
+        Self {
+            address: text.as_ptr(),
+            len: text.len(),
+        }
+    }
+}
+
+impl uDisplay for PmStr {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        let mut buf = [0; CHUNK];
+        let mut start = 0;
+        while start < self.len {
+            let len = CHUNK.min(self.len - start);
+            for (i, b) in buf[..len].iter_mut().enumerate() {
+                // SAFETY: the address lies within the text
+                *b = read_byte(unsafe { self.address.add(start + i) });
+            }
+            // SAFETY: the text is ASCII, so every chunk is valid UTF-8
+            f.write_str(unsafe { core::str::from_utf8_unchecked(&buf[..len]) })?;
+            start += len;
+        }
+        Ok(())
+    }
+}
+
+/// Copies `text` into an array for the static of `progmem!`.
+pub const fn to_array<const N: usize>(text: &[u8]) -> [u8; N] {
+    let mut array = [0; N];
+    let mut i = 0;
+    while i < N {
+        array[i] = text[i];
+        i += 1;
+    }
+    array
+}
+
+pub const fn is_ascii(text: &[u8]) -> bool {
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] >= 0x80 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Reads a byte of the flash.
+pub fn read_byte(address: *const u8) -> u8 {
+    let byte: u8;
+    // SAFETY: `lpm` only reads the flash at the address in Z
+    unsafe { llvm_asm!("lpm $0, Z" : "=r"(byte) : "z"(address)) };
+    byte
+}
@@ -0,0 +1,240 @@
+#![allow(dead_code)]
+
+use crate::config;
+use crate::control;
+use crate::recipe;
+use ufmt::derive::uDebug;
+
+pub const LINE_LEN: usize = 32;
+
+/// Commands received over the serial line.
+///
+/// Single lowercase letters and digits are the one byte commands the app
+/// sends and are handled as soon as they arrive. Everything else is
+/// collected up to the next line break and parsed as a text command.
+#[derive(PartialEq)]
+pub enum Command {
+    Legacy(u8),
+    /// `RECIPE <recipe>`
+    Recipe(u8),
+    /// `STEP <recipe> <step> <pattern> <seconds>`
+    Step(u8, u8, u8, i16),
+    /// `STEPS <recipe> <count>`
+    Steps(u8, u8),
+    /// `VALVES`
+    Valves,
+    /// `VALVES <job> <pattern>`
+    SetValves(u8, u8),
+    /// `MANUAL <pattern>`
+    Manual(u8),
+    /// `INTERLOCKS`
+    Interlocks,
+    /// `INTERLOCKS <index> <rule>`
+    SetInterlock(u8, u8),
+    /// `SETTLE`
+    Settle,
+    /// `SETTLE <valve> <tenths>`
+    SetSettle(u8, u8),
+    /// `ACK`
+    Acknowledge,
+    /// `CLEAR`
+    Clear,
+    /// `STUCK`
+    StuckTimeout,
+    /// `STUCK <seconds>`
+    SetStuckTimeout(u8),
+    /// `PUMP`
+    Pump,
+    /// `PUMP <on tenths> <off tenths>`
+    SetPump(u8, u8),
+    /// `LOG [<page>]`
+    Log(u8),
+    /// `FACTORY`
+    FactoryReset,
+    /// `LEAK`
+    Leak,
+    /// `LEAK <zone> <on> <off> <action>`
+    SetLeak(u8, u16, u16, u8),
+    /// `REBOOT`
+    Reboot,
+    /// `BOOTLOADER`
+    Bootloader,
+    /// `INFO`
+    Info,
+    /// `DIAG`
+    Diag,
+}
+
+#[derive(uDebug, PartialEq)]
+pub enum Error {
+    Syntax,
+    Unknown,
+    Range,
+    Invalid,
+    Storage,
+    Interlock,
+    Unacknowledged,
+    Wet,
+    /// The controller lacks what the command needs, like a bootloader it can start.
+    Unsupported,
+}
+
+impl From<control::Error> for Error {
+    fn from(e: control::Error) -> Self {
+        match e {
+            control::Error::Interlock(_) => Error::Interlock,
+            control::Error::Unacknowledged => Error::Unacknowledged,
+            control::Error::Wet => Error::Wet,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Self {
+        match e {
+            config::Error::Job | config::Error::Interlock | config::Error::Valve => Error::Range,
+            config::Error::Pattern
+            | config::Error::Timeout
+            | config::Error::Threshold
+            | config::Error::Action => Error::Invalid,
+            config::Error::Forbidden(_) => Error::Interlock,
+            config::Error::Step(e) => e.into(),
+        }
+    }
+}
+
+impl From<recipe::Error> for Error {
+    fn from(e: recipe::Error) -> Self {
+        match e {
+            recipe::Error::Recipe | recipe::Error::Step => Error::Range,
+            recipe::Error::Pattern | recipe::Error::Duration => Error::Invalid,
+        }
+    }
+}
+
+pub struct LineBuffer {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    overflow: bool,
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; LINE_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Whether no part of a command has been received.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.overflow
+    }
+
+    /// Feeds one received byte, returns the command once one is complete.
+    pub fn push(&mut self, b: u8) -> Option<Result<Command, Error>> {
+        match b {
+            b'\r' | b'\n' => {
+                if self.len == 0 && !self.overflow {
+                    return None;
+                }
+                let result = if self.overflow {
+                    Err(Error::Syntax)
+                } else {
+                    parse(&self.buf[..self.len])
+                };
+                self.len = 0;
+                self.overflow = false;
+                Some(result)
+            }
+            b'a'..=b'z' | b'0'..=b'9' if self.len == 0 && !self.overflow => {
+                Some(Ok(Command::Legacy(b)))
+            }
+            _ => {
+                if self.len < LINE_LEN {
+                    self.buf[self.len] = b;
+                    self.len += 1;
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+fn parse(line: &[u8]) -> Result<Command, Error> {
+    let mut args = line.split(|b| *b == b' ').filter(|a| !a.is_empty());
+    let command = match args.next().ok_or(Error::Syntax)? {
+        b"RECIPE" => Command::Recipe(number(args.next())?),
+        b"STEP" => Command::Step(
+            number(args.next())?,
+            number(args.next())?,
+            number(args.next())?,
+            number(args.next())?,
+        ),
+        b"STEPS" => Command::Steps(number(args.next())?, number(args.next())?),
+        b"VALVES" => match args.next() {
+            None => Command::Valves,
+            job => Command::SetValves(number(job)?, number(args.next())?),
+        },
+        b"MANUAL" => Command::Manual(number(args.next())?),
+        b"INTERLOCKS" => match args.next() {
+            None => Command::Interlocks,
+            index => Command::SetInterlock(number(index)?, number(args.next())?),
+        },
+        b"SETTLE" => match args.next() {
+            None => Command::Settle,
+            valve => Command::SetSettle(number(valve)?, number(args.next())?),
+        },
+        b"ACK" => Command::Acknowledge,
+        b"CLEAR" => Command::Clear,
+        b"STUCK" => match args.next() {
+            None => Command::StuckTimeout,
+            seconds => Command::SetStuckTimeout(number(seconds)?),
+        },
+        b"PUMP" => match args.next() {
+            None => Command::Pump,
+            on => Command::SetPump(number(on)?, number(args.next())?),
+        },
+        b"LOG" => match args.next() {
+            None => Command::Log(0),
+            page => Command::Log(number(page)?),
+        },
+        b"FACTORY" => Command::FactoryReset,
+        b"LEAK" => match args.next() {
+            None => Command::Leak,
+            zone => Command::SetLeak(
+                number(zone)?,
+                number(args.next())?,
+                number(args.next())?,
+                number(args.next())?,
+            ),
+        },
+        b"REBOOT" => Command::Reboot,
+        b"BOOTLOADER" => Command::Bootloader,
+        b"INFO" => Command::Info,
+        b"DIAG" => Command::Diag,
+        _ => return Err(Error::Unknown),
+    };
+    if args.next().is_some() {
+        return Err(Error::Syntax);
+    }
+    Ok(command)
+}
+
+fn number<T: core::convert::TryFrom<u16>>(arg: Option<&[u8]>) -> Result<T, Error> {
+    let arg = arg.ok_or(Error::Syntax)?;
+    let mut value: u16 = 0;
+    for b in arg {
+        if !b.is_ascii_digit() {
+            return Err(Error::Syntax);
+        }
+        value = value
+            .checked_mul(10)
+            .and_then(|v| v.checked_add((b - b'0') as u16))
+            .ok_or(Error::Range)?;
+    }
+    T::try_from(value).map_err(|_| Error::Range)
+}
@@ -0,0 +1,179 @@
+#![allow(dead_code)]
+
+use crate::config::{ValveTable, JOB_CLEAN};
+use crate::time::{DateTime, Duration};
+use ufmt::derive::uDebug;
+use ufmt::{uDebug, uWrite, uwrite};
+
+pub const MAX_STEPS: usize = 8;
+
+/// Recipe run every night between 3 and 4 o'clock.
+pub const NIGHTLY: u8 = 0;
+/// Recipe run before and after every filter job.
+pub const RINSE: u8 = 1;
+pub const RECIPE_COUNT: usize = 2;
+
+pub enum Error {
+    Recipe,
+    Step,
+    Pattern,
+    Duration,
+}
+
+/// One phase of a cleaning recipe: a valve pattern held for a duration.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Step {
+    pub pattern: u8,
+    pub duration: Duration,
+}
+
+impl Step {
+    pub const fn new(pattern: u8, duration: Duration) -> Self {
+        Self { pattern, duration }
+    }
+}
+
+impl uDebug for Step {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(
+            f,
+            r#"{{"pattern": {}, "duration": {}}}"#,
+            self.pattern,
+            self.duration.seconds()
+        )
+    }
+}
+
+/// An ordered list of steps, executed one after another.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Recipe {
+    steps: [Step; MAX_STEPS],
+    len: u8,
+}
+
+impl Recipe {
+    pub const fn empty() -> Self {
+        Self {
+            steps: [Step::new(0, Duration(0)); MAX_STEPS],
+            len: 0,
+        }
+    }
+
+    pub fn from_steps(steps: &[Step]) -> Self {
+        let mut recipe = Self::empty();
+        for (i, step) in steps.iter().take(MAX_STEPS).enumerate() {
+            recipe.steps[i] = *step;
+            recipe.len += 1;
+        }
+        recipe
+    }
+
+    pub fn len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn step(&self, index: u8) -> Option<Step> {
+        if index < self.len {
+            Some(self.steps[index as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the step at `index`, or appends it if `index` is the current length.
+    /// The pattern of the step has to pass the validation rule of the clean job.
+    pub fn set_step(&mut self, index: u8, step: Step) -> Result<(), Error> {
+        if index > self.len || index as usize >= MAX_STEPS {
+            return Err(Error::Step);
+        }
+        if !ValveTable::is_valid(JOB_CLEAN, step.pattern) {
+            return Err(Error::Pattern);
+        }
+        if step.duration.seconds() <= 0 {
+            return Err(Error::Duration);
+        }
+        self.steps[index as usize] = step;
+        if index == self.len {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    /// Shortens the recipe to `len` steps.
+    pub fn truncate(&mut self, len: u8) -> Result<(), Error> {
+        if len > self.len {
+            return Err(Error::Step);
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
+impl uDebug for Recipe {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        f.write_str("[")?;
+        for i in 0..self.len {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            uwrite!(f, "{:?}", self.steps[i as usize])?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Progress of a running recipe.
+#[derive(Clone, Copy, PartialEq, uDebug)]
+pub struct CleanState {
+    pub recipe: u8,
+    pub step: u8,
+    pub step_end: DateTime,
+}
+
+impl CleanState {
+    /// Starts `recipe` at its first step, `None` if the recipe has no steps.
+    pub fn start(recipes: &[Recipe], recipe: u8, now: DateTime) -> Option<Self> {
+        Self::at_step(recipes, recipe, 0, now)
+    }
+
+    /// Moves on to the following step, `None` once the recipe is finished.
+    pub fn next_step(&self, recipes: &[Recipe], now: DateTime) -> Option<Self> {
+        Self::at_step(recipes, self.recipe, self.step + 1, now)
+    }
+
+    /// Starts the current step over, used while its valves are still moving.
+    pub fn restart(self, recipes: &[Recipe], now: DateTime) -> Self {
+        Self::at_step(recipes, self.recipe, self.step, now).unwrap_or(self)
+    }
+
+    pub fn pattern(&self, recipes: &[Recipe]) -> u8 {
+        recipes
+            .get(self.recipe as usize)
+            .and_then(|r| r.step(self.step))
+            .map_or(0, |s| s.pattern)
+    }
+
+    pub fn is_finished(&self, now: DateTime) -> bool {
+        now.ge(&self.step_end)
+    }
+
+    fn at_step(recipes: &[Recipe], recipe: u8, step: u8, now: DateTime) -> Option<Self> {
+        let s = recipes.get(recipe as usize)?.step(step)?;
+        Some(Self {
+            recipe,
+            step,
+            // durations are positive, `set_step` refuses others
+            step_end: now.add_seconds(s.duration.seconds() as u32),
+        })
+    }
+}
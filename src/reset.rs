@@ -0,0 +1,254 @@
+use crate::progmem::{self, PmStr};
+use core::mem::MaybeUninit;
+use core::panic::Location;
+use core::ptr;
+use ufmt::{uDebug, uWrite, uwrite};
+
+/// Reset flags in MCUSR.
+pub const PORF: u8 = 1 << 0;
+pub const EXTRF: u8 = 1 << 1;
+pub const BORF: u8 = 1 << 2;
+pub const WDRF: u8 = 1 << 3;
+const ALL_FLAGS: u8 = PORF | EXTRF | BORF | WDRF;
+
+const MAGIC: u16 = 0xB007;
+const PANIC_MAGIC: u16 = 0xDEAD;
+/// Marks a request to enter the bootloader after the next reset.
+const BOOTLOADER_MAGIC: u16 = 0xB0B0;
+/// Start of Optiboot in the flash of the ATmega328, as a word address.
+const BOOTLOADER: usize = 0x7E00 / 2;
+/// Optiboot keeps its version in the last word of the flash, the minor version first.
+const BOOTLOADER_VERSION: usize = 0x7FFE;
+/// First major version of Optiboot that waits for the host when it is jumped to.
+const MIN_BOOTLOADER_MAJOR: u8 = 6;
+/// Characters kept from the end of the file name of a panic.
+const FILE_LEN: usize = 16;
+
+/// State that survives a reset in RAM the startup code does not clear.
+#[repr(C)]
+struct NoInit {
+    magic: u16,
+    reboots: u16,
+    panic_magic: u16,
+    panic: PanicLocation,
+    bootloader: u16,
+}
+
+// SAFETY: only read and written as a whole with volatile accesses, never from an interrupt,
+// and any bit pattern is a valid `NoInit`
+#[link_section = ".noinit"]
+static mut NOINIT: MaybeUninit<NoInit> = MaybeUninit::uninit();
+
+/// The reset flags Optiboot passes in r2, saved by `save_optiboot_flags`.
+#[no_mangle]
+#[link_section = ".noinit"]
+static mut OPTIBOOT_FLAGS: MaybeUninit<u8> = MaybeUninit::uninit();
+
+/// Saves r2 before the startup code overwrites it.
+///
+/// Optiboot reads MCUSR, clears it, so it can tell its own watchdog reset from one of the
+/// firmware, and hands the flags over in r2. This runs first in `.init0`, before the stack
+/// and the zero register are set up, and falls through into the rest of the startup code.
+#[naked]
+#[no_mangle]
+#[link_section = ".init0"]
+unsafe extern "C" fn save_optiboot_flags() {
+    llvm_asm!("sts OPTIBOOT_FLAGS, r2" :::: "volatile");
+    // no return, the next section follows right after
+    core::hint::unreachable_unchecked()
+}
+
+/// The reset flags of the last reset, from r2 where Optiboot left them or else from `mcusr`.
+///
+/// Without Optiboot r2 holds whatever the code before the reset left in it, so it is only
+/// taken if it holds reset flags and nothing else.
+pub fn reset_flags(mcusr: u8) -> u8 {
+    // SAFETY: written once in `.init0`, before `main`
+    let optiboot = unsafe { ptr::read_volatile(OPTIBOOT_FLAGS.as_ptr()) };
+    if optiboot != 0 && optiboot & !ALL_FLAGS == 0 {
+        optiboot
+    } else {
+        mcusr
+    }
+}
+
+/// Where the firmware panicked.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct PanicLocation {
+    pub line: u16,
+    pub column: u16,
+    /// End of the file name, padded with zeros.
+    file: [u8; FILE_LEN],
+}
+
+impl PanicLocation {
+    pub fn file(&self) -> &str {
+        let len = self.file.iter().position(|b| *b == 0).unwrap_or(FILE_LEN);
+        core::str::from_utf8(&self.file[..len]).unwrap_or("?")
+    }
+}
+
+impl uDebug for PanicLocation {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, "{}{}", progmem!(br#"{"file": ""#), self.file())?;
+        uwrite!(f, "{}{}", progmem!(br#"", "line": "#), self.line)?;
+        uwrite!(f, "{}{}", progmem!(br#", "column": "#), self.column)?;
+        f.write_str("}")
+    }
+}
+
+/// Why the controller started.
+#[derive(Clone, Copy)]
+pub struct ResetInfo {
+    /// The reset flags from Optiboot or MCUSR, zero if neither had any.
+    pub flags: u8,
+    /// Resets since the last power-on.
+    pub reboots: u16,
+    /// Where the firmware panicked before the reset.
+    pub panic: Option<PanicLocation>,
+}
+
+impl ResetInfo {
+    /// Reads what the last reset left behind and starts the record for the next one.
+    ///
+    /// `flags` come from `reset_flags`, with MCUSR read before the watchdog driver clears it.
+    /// After a power-on the RAM holds no record.
+    pub fn take(flags: u8) -> Self {
+        // SAFETY: see `NOINIT`
+        let noinit = unsafe { ptr::read_volatile(NOINIT.as_ptr()) };
+        let valid = noinit.magic == MAGIC && flags & PORF == 0;
+        let reboots = if valid {
+            noinit.reboots.saturating_add(1)
+        } else {
+            0
+        };
+        let panic = if valid && noinit.panic_magic == PANIC_MAGIC {
+            Some(noinit.panic)
+        } else {
+            None
+        };
+        let noinit = NoInit {
+            magic: MAGIC,
+            reboots,
+            panic_magic: 0,
+            panic: noinit.panic,
+            bootloader: 0,
+        };
+        // SAFETY: see `NOINIT`
+        unsafe { ptr::write_volatile(NOINIT.as_mut_ptr(), noinit) };
+        Self {
+            flags,
+            reboots,
+            panic,
+        }
+    }
+
+    pub fn cause(&self) -> PmStr {
+        if self.panic.is_some() {
+            progmem!(b"panic")
+        } else if self.flags & PORF != 0 {
+            progmem!(b"power_on")
+        } else if self.flags & BORF != 0 {
+            progmem!(b"brown_out")
+        } else if self.flags & WDRF != 0 {
+            progmem!(b"watchdog")
+        } else if self.flags & EXTRF != 0 {
+            progmem!(b"external")
+        } else {
+            progmem!(b"unknown")
+        }
+    }
+}
+
+impl uDebug for ResetInfo {
+    fn fmt<W: ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite,
+    {
+        uwrite!(f, "{}{}", progmem!(br#"{"cause": ""#), self.cause())?;
+        uwrite!(f, "{}{}", progmem!(br#"", "flags": "#), self.flags)?;
+        uwrite!(f, "{}{}", progmem!(br#", "reboots": "#), self.reboots)?;
+        uwrite!(f, "{}", progmem!(br#", "panic": "#))?;
+        match &self.panic {
+            Some(location) => uwrite!(f, "{:?}", location)?,
+            None => uwrite!(f, "{}", progmem!(b"null"))?,
+        }
+        f.write_str("}")
+    }
+}
+
+/// Has the next boot enter the bootloader instead of the firmware.
+pub fn request_bootloader() {
+    // SAFETY: see `NOINIT`
+    unsafe {
+        let mut noinit = ptr::read_volatile(NOINIT.as_ptr());
+        noinit.bootloader = BOOTLOADER_MAGIC;
+        ptr::write_volatile(NOINIT.as_mut_ptr(), noinit);
+    }
+}
+
+/// Whether the firmware asked for the bootloader before the reset with `flags`.
+/// The request is taken back, so the reset after that boots the firmware again.
+pub fn take_bootloader_request(flags: u8) -> bool {
+    // SAFETY: see `NOINIT`
+    let mut noinit = unsafe { ptr::read_volatile(NOINIT.as_ptr()) };
+    if noinit.magic != MAGIC || noinit.bootloader != BOOTLOADER_MAGIC || flags & PORF != 0 {
+        return false;
+    }
+    noinit.bootloader = 0;
+    // SAFETY: see `NOINIT`
+    unsafe { ptr::write_volatile(NOINIT.as_mut_ptr(), noinit) };
+    true
+}
+
+/// Major and minor version of Optiboot, `None` if the bootloader is missing or too old
+/// for `jump_to_bootloader`. Custom builds set the top bit of the major version.
+pub fn bootloader_version() -> Option<(u8, u8)> {
+    let minor = progmem::read_byte(BOOTLOADER_VERSION as *const u8);
+    let major = progmem::read_byte((BOOTLOADER_VERSION + 1) as *const u8);
+    // erased flash reads 0xFF
+    if major & 0x7F >= MIN_BOOTLOADER_MAJOR && major != 0xFF {
+        Some((major, minor))
+    } else {
+        None
+    }
+}
+
+/// Jumps to Optiboot.
+///
+/// # Safety
+///
+/// MCUSR has to be cleared before, Optiboot 6 and later only wait for the host when
+/// they are entered without a reset flag. Nothing may be set up that the bootloader
+/// does not expect, like running interrupts.
+pub unsafe fn jump_to_bootloader() -> ! {
+    let bootloader: extern "C" fn() -> ! = core::mem::transmute(BOOTLOADER);
+    bootloader()
+}
+
+/// Keeps the location of a panic for the next boot, called from the panic handler.
+pub fn record_panic(location: Option<&Location>) {
+    let mut panic = PanicLocation {
+        line: 0,
+        column: 0,
+        file: [0; FILE_LEN],
+    };
+    if let Some(location) = location {
+        panic.line = location.line() as u16;
+        panic.column = location.column() as u16;
+        let file = location.file().as_bytes();
+        let tail = &file[file.len().saturating_sub(FILE_LEN)..];
+        panic.file[..tail.len()].copy_from_slice(tail);
+    }
+    // SAFETY: see `NOINIT`, the panic handler runs with interrupts disabled
+    unsafe {
+        let mut noinit = ptr::read_volatile(NOINIT.as_ptr());
+        noinit.panic_magic = PANIC_MAGIC;
+        noinit.panic = panic;
+        ptr::write_volatile(NOINIT.as_mut_ptr(), noinit);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::checksum::checksum;
+use crate::config::{JOB_CLEAN, JOB_FILTER, JOB_IDLE};
+use crate::control::{ControlMode, Job};
+use crate::eeprom::{self, Eeprom};
+use crate::recipe::{CleanState, Recipe, RINSE};
+use crate::rtc::{self, Rtc};
+use crate::time::DateTime;
+
+/// Position of the job record in the DS1307 RAM.
+const RAM_OFFSET: u8 = 0;
+const MAGIC: u8 = 0x4A;
+/// Magic, job, recipe, step, step end, next job, time last seen and checksum.
+const RECORD_LEN: usize = 1 + 3 + 6 + 1 + 6 + 1;
+/// A job that was off for up to this many seconds carries on where it stopped.
+const RESUME_WITHIN: u32 = 5 * 60;
+/// Up to this many seconds a job starts over, after that it is dropped.
+const RESTART_WITHIN: u32 = 60 * 60;
+/// Seconds between writes of the record while the job does not change.
+const HEARTBEAT: u32 = 60;
+/// The same in the EEPROM, where every write wears the cells. It stays well below
+/// `RESUME_WITHIN`, a short outage looks longer by up to this much and must still resume.
+const EEPROM_HEARTBEAT: u32 = 2 * 60;
+const _: [(); 1] = [(); (EEPROM_HEARTBEAT <= RESUME_WITHIN / 2) as usize];
+/// Without RAM in the clock the record rotates over this many slots in the EEPROM between
+/// the config and the event log, from 416 up to 511.
+const SLOT_COUNT: usize = 5;
+const FIRST_SLOT: u16 = 416;
+/// Generation and record.
+const SLOT_LEN: usize = 1 + RECORD_LEN;
+
+pub enum Error {
+    Rtc(rtc::Error),
+    Eeprom(eeprom::Error),
+}
+
+impl From<rtc::Error> for Error {
+    fn from(e: rtc::Error) -> Self {
+        Error::Rtc(e)
+    }
+}
+
+impl From<eeprom::Error> for Error {
+    fn from(e: eeprom::Error) -> Self {
+        Error::Eeprom(e)
+    }
+}
+
+/// What became of an interrupted job after a reboot.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Resumed,
+    Restarted,
+    Abandoned,
+}
+
+/// The automatic job that was running, so it can be picked up again after a power loss.
+#[derive(Clone, Copy, PartialEq)]
+pub struct JobRecord {
+    job: Job,
+    next_job: Job,
+    /// Last time the controller was known to be running.
+    seen: DateTime,
+}
+
+impl JobRecord {
+    /// Only automatic jobs are resumed, any other mode is recorded as idle.
+    pub fn new(mode: &ControlMode, now: DateTime) -> Self {
+        let (job, next_job) = match mode {
+            ControlMode::Automatic(job, next_job) => (*job, *next_job),
+            _ => (Job::Idle, Job::Idle),
+        };
+        Self {
+            job,
+            next_job,
+            seen: now,
+        }
+    }
+
+    /// Whether `self` has to be written over the `stored` record: the job changed or,
+    /// while a job runs, the time last seen is `heartbeat` seconds old. The time of an idle
+    /// record is never looked at.
+    pub fn is_due(&self, stored: &Option<JobRecord>, heartbeat: u32) -> bool {
+        match stored {
+            Some(stored) => {
+                stored.job != self.job
+                    || stored.next_job != self.next_job
+                    || (self.job != Job::Idle
+                        && self.seen.timestamp().wrapping_sub(stored.seen.timestamp()) >= heartbeat)
+            }
+            None => true,
+        }
+    }
+
+    /// Job number of the recorded job.
+    pub fn job(&self) -> u8 {
+        job_kind(&self.job)
+    }
+
+    /// Reads the record from the RAM of the clock, `None` if none was stored yet.
+    /// The DS3231 has no RAM and fails with `Unsupported`.
+    pub fn load<R: Rtc>(rtc: &mut R) -> Result<Option<Self>, rtc::Error> {
+        let mut buf = [0; RECORD_LEN];
+        rtc.read_ram(RAM_OFFSET, &mut buf)?;
+        Ok(Self::from_bytes(&buf))
+    }
+
+    pub fn store<R: Rtc>(&self, rtc: &mut R) -> Result<(), rtc::Error> {
+        rtc.write_ram(RAM_OFFSET, &self.to_bytes())
+    }
+
+    fn from_bytes(buf: &[u8; RECORD_LEN]) -> Option<Self> {
+        if buf[0] != MAGIC || buf[RECORD_LEN - 1] != checksum(&buf[..RECORD_LEN - 1]) {
+            return None;
+        }
+        let job = match buf[1] {
+            JOB_FILTER => Job::Filter,
+            JOB_CLEAN => {
+                let mut step_end = [0; 6];
+                step_end.copy_from_slice(&buf[4..10]);
+                Job::Clean(CleanState {
+                    recipe: buf[2],
+                    step: buf[3],
+                    step_end: DateTime::from_bytes(&step_end),
+                })
+            }
+            _ => Job::Idle,
+        };
+        // the job queued after a clean is never a clean itself
+        let next_job = match buf[10] {
+            JOB_FILTER => Job::Filter,
+            _ => Job::Idle,
+        };
+        let mut seen = [0; 6];
+        seen.copy_from_slice(&buf[11..17]);
+        Some(Self {
+            job,
+            next_job,
+            seen: DateTime::from_bytes(&seen),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0; RECORD_LEN];
+        buf[0] = MAGIC;
+        buf[1] = job_kind(&self.job);
+        if let Job::Clean(state) = self.job {
+            buf[2] = state.recipe;
+            buf[3] = state.step;
+            buf[4..10].copy_from_slice(&state.step_end.to_bytes());
+        }
+        buf[10] = job_kind(&self.next_job);
+        buf[11..17].copy_from_slice(&self.seen.to_bytes());
+        buf[RECORD_LEN - 1] = checksum(&buf[..RECORD_LEN - 1]);
+        buf
+    }
+
+    /// Picks up the recorded job at `now`, depending on how long the controller was off.
+    ///
+    /// After a short outage the job carries on, a clean with the time that was left of
+    /// its step. After a longer one a clean starts its recipe over and filtering goes
+    /// through a rinse first. Beyond that, or if the clock went backwards, the job is
+    /// dropped and the controller starts idle.
+    pub fn resume(&self, recipes: &[Recipe], now: DateTime) -> Option<(ControlMode, Outcome)> {
+        if self.job == Job::Idle {
+            return None;
+        }
+        let abandoned = (
+            ControlMode::Automatic(Job::Idle, Job::Idle),
+            Outcome::Abandoned,
+        );
+        let off = match now.timestamp().checked_sub(self.seen.timestamp()) {
+            Some(off) if off <= RESTART_WITHIN => off,
+            _ => return Some(abandoned),
+        };
+        if off <= RESUME_WITHIN {
+            let job = match self.job {
+                Job::Clean(state) => {
+                    let left = state
+                        .step_end
+                        .timestamp()
+                        .saturating_sub(self.seen.timestamp());
+                    let step_end = now.add_seconds(left);
+                    // the recipe may have lost the step since
+                    recipes
+                        .get(state.recipe as usize)
+                        .and_then(|recipe| recipe.step(state.step))
+                        .map(|_| Job::Clean(CleanState { step_end, ..state }))
+                }
+                job => Some(job),
+            };
+            if let Some(job) = job {
+                return Some((ControlMode::Automatic(job, self.next_job), Outcome::Resumed));
+            }
+        }
+        let restarted = match self.job {
+            Job::Clean(state) => CleanState::start(recipes, state.recipe, now)
+                .map(|state| ControlMode::Automatic(Job::Clean(state), self.next_job)),
+            _ => Some(match CleanState::start(recipes, RINSE, now) {
+                Some(state) => ControlMode::Automatic(Job::Clean(state), Job::Filter),
+                None => ControlMode::Automatic(Job::Filter, Job::Idle),
+            }),
+        };
+        Some(restarted.map_or(abandoned, |mode| (mode, Outcome::Restarted)))
+    }
+}
+
+/// Where the job record is kept.
+///
+/// The DS1307 keeps it in its RAM. The DS3231 has none, there it goes into the next of five
+/// slots in the EEPROM with a generation counter, so a cell is only written on every fifth
+/// write and a power loss while a slot is written falls back to the slot before.
+pub enum JobStore {
+    Rtc,
+    Eeprom {
+        /// Slot of the newest record, the next one goes into the slot after it.
+        current: usize,
+        generation: u8,
+    },
+}
+
+impl JobStore {
+    /// Reads the record from the RAM of the clock, or from the EEPROM if the clock has no
+    /// RAM. The record is `None` if none was stored yet.
+    pub fn load<R: Rtc>(rtc: &mut R, eeprom: &Eeprom) -> Result<(Self, Option<JobRecord>), Error> {
+        match JobRecord::load(rtc) {
+            Err(rtc::Error::Unsupported) => (),
+            result => return Ok((JobStore::Rtc, result?)),
+        }
+        let mut current = SLOT_COUNT - 1;
+        let mut generation = 0;
+        let mut newest = None;
+        for slot in 0..SLOT_COUNT {
+            let mut buf = [0; SLOT_LEN];
+            eeprom.read(slot_offset(slot), &mut buf)?;
+            let mut record = [0; RECORD_LEN];
+            record.copy_from_slice(&buf[1..]);
+            if let Some(record) = JobRecord::from_bytes(&record) {
+                if newest.is_none() || buf[0].wrapping_sub(generation) as i8 > 0 {
+                    current = slot;
+                    generation = buf[0];
+                    newest = Some(record);
+                }
+            }
+        }
+        Ok((
+            JobStore::Eeprom {
+                current,
+                generation,
+            },
+            newest,
+        ))
+    }
+
+    /// Seconds between writes of the record while the job does not change.
+    pub fn heartbeat(&self) -> u32 {
+        match self {
+            JobStore::Rtc => HEARTBEAT,
+            JobStore::Eeprom { .. } => EEPROM_HEARTBEAT,
+        }
+    }
+
+    pub fn store<R: Rtc>(
+        &mut self,
+        record: &JobRecord,
+        rtc: &mut R,
+        eeprom: &mut Eeprom,
+    ) -> Result<(), Error> {
+        match self {
+            JobStore::Rtc => record.store(rtc)?,
+            JobStore::Eeprom {
+                current,
+                generation,
+            } => {
+                let slot = (*current + 1) % SLOT_COUNT;
+                let next = generation.wrapping_add(1);
+                // the generation goes last, a slot is only the newest once it is complete
+                eeprom.write(slot_offset(slot) + 1, &record.to_bytes())?;
+                eeprom.write(slot_offset(slot), &[next])?;
+                *current = slot;
+                *generation = next;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn slot_offset(slot: usize) -> u16 {
+    FIRST_SLOT + (slot * SLOT_LEN) as u16
+}
+
+fn job_kind(job: &Job) -> u8 {
+    match job {
+        Job::Idle => JOB_IDLE,
+        Job::Filter => JOB_FILTER,
+        Job::Clean(_) => JOB_CLEAN,
+    }
+}
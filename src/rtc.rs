@@ -0,0 +1,60 @@
+use crate::time::DateTime;
+
+/// Most registers any of the clocks dumps with `read_registers`.
+pub const MAX_REGISTERS: usize = 19;
+
+pub enum Error {
+    I2C,
+    /// Access outside of the battery backed RAM.
+    RamRange,
+    /// The clock has no such feature, like RAM on the DS3231.
+    Unsupported,
+}
+
+/// Output of the square wave pin, which is open drain and needs a pull-up.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SquareWave {
+    /// Falls when the seconds register counts up.
+    Hz1,
+    Hz4096,
+    Hz8192,
+    Hz32768,
+    /// No square wave, the pin is held high (`true`) or low.
+    Level(bool),
+}
+
+/// A battery backed real time clock on the I2C bus.
+pub trait Rtc {
+    fn get_datetime(&mut self) -> Result<DateTime, Error>;
+
+    /// Lets the oscillator run, without touching the registers if it already does.
+    fn start(&mut self) -> Result<(), Error>;
+
+    fn set_square_wave(&mut self, rate: SquareWave) -> Result<(), Error>;
+
+    /// Reads `data.len()` bytes of the battery backed RAM, starting at `offset`.
+    fn read_ram(&mut self, offset: u8, data: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes `data` to the battery backed RAM, starting at `offset`.
+    fn write_ram(&mut self, offset: u8, data: &[u8]) -> Result<(), Error>;
+
+    /// Reads the time and control registers as they are into the start of `data`
+    /// and returns how many there are.
+    fn read_registers(&mut self, data: &mut [u8; MAX_REGISTERS]) -> Result<usize, Error>;
+
+    /// Failed I2C transfers since the driver was created.
+    fn errors(&self) -> u16;
+
+    /// Temperature of the clock in quarters of a degree Celsius, if it measures one.
+    fn temperature(&mut self) -> Result<Option<i16>, Error> {
+        Ok(None)
+    }
+}
+
+pub fn packed_bcd_to_decimal(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0xF)
+}
+
+pub fn decimal_to_packed_bcd(dec: u8) -> u8 {
+    ((dec / 10) << 4) | (dec % 10)
+}
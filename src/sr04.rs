@@ -8,6 +8,8 @@ pub struct SR04<PINTRIG, PINECHO> {
     timer1: TC1,
     trig: Pin<Output, PINTRIG>,
     echo: Pin<Input, PINECHO>,
+    /// Timer1 counts of the last echo.
+    echo_count: Option<u16>,
 }
 
 impl<PINTRIG, PINECHO> SR04<PINTRIG, PINECHO>
@@ -16,7 +18,17 @@ where
     PINECHO: avr_hal_generic::port::PinOps,
 {
     pub fn new(timer1: TC1, trig: Pin<Output, PINTRIG>, echo: Pin<Input, PINECHO>) -> Self {
-        Self { timer1, trig, echo }
+        Self {
+            timer1,
+            trig,
+            echo,
+            echo_count: None,
+        }
+    }
+
+    /// Length of the last echo in Timer1 counts of 4 µs, `None` if there was none.
+    pub fn echo_count(&self) -> Option<u16> {
+        self.echo_count
     }
 
     pub fn measure_distance(&mut self) -> Option<u16> {
@@ -35,6 +47,7 @@ where
             // 0.2s/4µs = 50000
             if self.timer1.tcnt1.read().bits() >= 50000 {
                 // jump to the beginning of the outer loop if no obstacle is detected
+                self.echo_count = None;
                 return None;
             }
         }
@@ -50,7 +63,9 @@ where
         // some HC-SR04 labeled sensor holds the echo pin in high state for very long time,
         // thus overflowing the u16 value when multiplying the timer1 value with 4.
         // overflow during runtime causes panic! so it must be handled
-        let temp_timer = self.timer1.tcnt1.read().bits().saturating_mul(4);
+        let count = self.timer1.tcnt1.read().bits();
+        self.echo_count = Some(count);
+        let temp_timer = count.saturating_mul(4);
         let value = match temp_timer {
             u16::MAX => {
                 return None;
@@ -60,7 +75,9 @@ where
 
         // Await 100 ms before sending the next trig
         // 0.1s/4µs = 25000
-        while self.timer1.tcnt1.read().bits() < 25000 {}
+        while self.timer1.tcnt1.read().bits() < 25000 {
+            crate::power::idle();
+        }
 
         return Some(value);
     }
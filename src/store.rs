@@ -0,0 +1,91 @@
+use crate::checksum::crc16;
+use crate::config::{Config, CONFIG_LEN, CONFIG_VERSION};
+use crate::eeprom::{self, Eeprom};
+
+/// The config rotates over this many slots in the EEPROM after the latched alarms,
+/// from 16 up to 416.
+const SLOT_COUNT: usize = 5;
+const FIRST_SLOT: u16 = 16;
+/// Room for the config and its header, with a few bytes to spare.
+const SLOT_LEN: usize = 80;
+const MAGIC: u8 = 0xC5;
+/// Magic, layout version, generation, length and CRC of the config.
+const HEADER_LEN: usize = 6;
+const _: [(); 1] = [(); (HEADER_LEN + CONFIG_LEN <= SLOT_LEN) as usize];
+
+/// Keeps the config in the EEPROM.
+///
+/// Every change of the config goes into the next of five slots, each with a header holding
+/// the layout version, a generation counter and a CRC. A cell is thus only written on every
+/// fifth change, and if power fails while a slot is written, the slot before still holds
+/// the previous config.
+pub struct ConfigStore {
+    /// Slot of the newest config, the next one goes into the slot after it.
+    current: usize,
+    generation: u8,
+}
+
+impl ConfigStore {
+    /// Reads the newest valid config, `None` if none was stored yet.
+    pub fn load(eeprom: &Eeprom) -> Result<(Self, Option<Config>), eeprom::Error> {
+        let mut store = Self {
+            current: SLOT_COUNT - 1,
+            generation: 0,
+        };
+        let mut newest = None;
+        for slot in 0..SLOT_COUNT {
+            if let Some((generation, config)) = Self::read_slot(eeprom, slot)? {
+                if newest.is_none() || generation.wrapping_sub(store.generation) as i8 > 0 {
+                    store.current = slot;
+                    store.generation = generation;
+                    newest = Some(config);
+                }
+            }
+        }
+        Ok((store, newest))
+    }
+
+    /// Writes `config` into the slot after the one holding the newest config.
+    pub fn store(&mut self, eeprom: &mut Eeprom, config: &Config) -> Result<(), eeprom::Error> {
+        let slot = (self.current + 1) % SLOT_COUNT;
+        let generation = self.generation.wrapping_add(1);
+        let payload = config.to_bytes();
+        let crc = crc16(&payload).to_le_bytes();
+        let header = [
+            MAGIC,
+            CONFIG_VERSION,
+            generation,
+            CONFIG_LEN as u8,
+            crc[0],
+            crc[1],
+        ];
+        // the header goes last, a slot is only valid once it is complete
+        let offset = slot_offset(slot);
+        eeprom.write(offset + HEADER_LEN as u16, &payload)?;
+        eeprom.write(offset, &header)?;
+        self.current = slot;
+        self.generation = generation;
+        Ok(())
+    }
+
+    /// Generation and config of `slot`, `None` if it holds no valid config of this layout
+    /// version.
+    fn read_slot(eeprom: &Eeprom, slot: usize) -> Result<Option<(u8, Config)>, eeprom::Error> {
+        let offset = slot_offset(slot);
+        let mut header = [0; HEADER_LEN];
+        eeprom.read(offset, &mut header)?;
+        if header[0] != MAGIC || header[1] != CONFIG_VERSION || header[3] as usize != CONFIG_LEN {
+            return Ok(None);
+        }
+        let mut payload = [0; CONFIG_LEN];
+        eeprom.read(offset + HEADER_LEN as u16, &mut payload)?;
+        if crc16(&payload).to_le_bytes() != [header[4], header[5]] {
+            return Ok(None);
+        }
+        Ok(Config::from_bytes(&payload).map(|config| (header[2], config)))
+    }
+}
+
+fn slot_offset(slot: usize) -> u16 {
+    FIRST_SLOT + (slot * SLOT_LEN) as u16
+}
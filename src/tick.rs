@@ -0,0 +1,120 @@
+use crate::millis;
+use crate::time::DateTime;
+use arduino_hal::pac::{EXINT, PORTD};
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+/// D2 is PD2 (PCINT18) and RXD is PD0 (PCINT16), both in PCMSK2.
+const TICK_PIN: u8 = 1 << 2;
+const RXD_PIN: u8 = 1 << 0;
+/// Pin change interrupt 2 in PCICR, for the pins of port D.
+const PCIE2: u8 = 1 << 2;
+/// Time without a tick after which the square wave counts as stopped, in milliseconds.
+const TICK_TIMEOUT_MS: u32 = 1500;
+/// Seconds the time is carried on by the ticks before the RTC is read again.
+const RTC_READ_TICKS: u32 = 60;
+
+static TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+/// `millis` at the last tick.
+static LAST_TICK: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+/// Level of D2 at the last pin change, to tell a falling edge.
+static TICK_HIGH: Mutex<Cell<bool>> = Mutex::new(Cell::new(true));
+/// Set when a start bit on RXD raised the pin change interrupt.
+static RX_WAKE: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Counts the falling edges on D2, where the 1 Hz square wave of the clock comes in.
+///
+/// A pin change interrupt is used instead of INT0, because only a pin change wakes the
+/// CPU from power-down on an edge.
+pub fn init(exint: &EXINT) {
+    avr_device::interrupt::free(|cs| {
+        TICKS.borrow(cs).set(0);
+        LAST_TICK.borrow(cs).set(millis::millis());
+    });
+    exint
+        .pcmsk2
+        .modify(|r, w| unsafe { w.bits(r.bits() | TICK_PIN) });
+    exint
+        .pcicr
+        .modify(|r, w| unsafe { w.bits(r.bits() | PCIE2) });
+}
+
+/// Seconds since `init`, as counted by the clock.
+pub fn ticks() -> u32 {
+    avr_device::interrupt::free(|cs| TICKS.borrow(cs).get())
+}
+
+/// Whether a tick came within the last 1.5 s. Timer0 stands still in power-down, so a
+/// square wave that stops there is only caught by the watchdog.
+pub fn is_running() -> bool {
+    let last = avr_device::interrupt::free(|cs| LAST_TICK.borrow(cs).get());
+    millis::millis().wrapping_sub(last) < TICK_TIMEOUT_MS
+}
+
+/// The time of the RTC, carried on by the ticks between two reads.
+pub struct TickClock {
+    /// The last time read and the ticks at the read.
+    read: Option<(DateTime, u32)>,
+}
+
+impl TickClock {
+    pub const fn new() -> Self {
+        Self { read: None }
+    }
+
+    /// The time of the last read plus the ticks since, `None` once the RTC has to be read
+    /// again or while the square wave is missing.
+    pub fn now(&self) -> Option<DateTime> {
+        let (time, at) = self.read?;
+        let elapsed = ticks().wrapping_sub(at);
+        if elapsed >= RTC_READ_TICKS || !is_running() {
+            return None;
+        }
+        Some(time.add_seconds(elapsed))
+    }
+
+    /// Takes the time just read from the RTC.
+    pub fn set(&mut self, time: DateTime) {
+        self.read = Some((time, ticks()));
+    }
+}
+
+/// Lets a start bit on RXD wake the CPU from power-down, where the UART does not run.
+/// Off while awake, so received bytes do not raise an interrupt for every bit.
+pub fn watch_rxd(watch: bool) {
+    // SAFETY: PCMSK2 is only changed here and in `init`, with interrupts disabled
+    let exint = unsafe { &*EXINT::ptr() };
+    avr_device::interrupt::free(|_| {
+        exint.pcmsk2.modify(|r, w| unsafe {
+            w.bits(if watch {
+                r.bits() | RXD_PIN
+            } else {
+                r.bits() & !RXD_PIN
+            })
+        })
+    });
+}
+
+/// Whether serial data woke the CPU since the last call. The byte that woke it is lost.
+pub fn take_rx_wake() -> bool {
+    avr_device::interrupt::free(|cs| RX_WAKE.borrow(cs).replace(false))
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT2() {
+    // SAFETY: only reads the input and mask registers
+    let pind = unsafe { &*PORTD::ptr() }.pind.read().bits();
+    let watched = unsafe { &*EXINT::ptr() }.pcmsk2.read().bits();
+    avr_device::interrupt::free(|cs| {
+        let high = pind & TICK_PIN != 0;
+        let was_high = TICK_HIGH.borrow(cs).replace(high);
+        if was_high && !high {
+            let ticks = TICKS.borrow(cs);
+            ticks.set(ticks.get().wrapping_add(1));
+            LAST_TICK.borrow(cs).set(millis::millis());
+        }
+        if watched & RXD_PIN != 0 && pind & RXD_PIN == 0 {
+            RX_WAKE.borrow(cs).set(true);
+        }
+    })
+}
@@ -48,28 +48,6 @@ impl Time {
                 + (self.second() as i16 - othertime.second() as i16),
         )
     }
-
-    pub fn add_duration(mut self, mut dur: Duration) -> Self {
-        while dur.0 > 0 {
-            if self.second == 60 {
-                self.second = 0;
-                if self.minute == 60 {
-                    self.minute = 0;
-                    if self.hour == 24 {
-                        self.hour = 0;
-                    } else {
-                        self.hour += 1;
-                    }
-                } else {
-                    self.minute += 1;
-                }
-            } else {
-                self.second += 1;
-            }
-            dur.0 -= 1;
-        }
-        self
-    }
 }
 
 impl Date {
@@ -103,35 +81,70 @@ impl Date {
 }
 
 impl DateTime {
-    pub fn add_duration(mut self, mut dur: Duration) -> Self {
-        while dur.0 > 0 {
-            if self.time.second == 60 {
-                self.time.second = 0;
-                if self.time.minute == 60 {
-                    self.time.minute = 0;
-                    if self.time.hour == 24 {
-                        self.time.hour = 0;
-                        if self.date.day == Date::get_days_in_month(self.date.month) {
-                            self.date.day = 1;
-                            if self.date.month == 12 {
-                                self.date.month = 1;
-                                self.date.year += 1;
-                            } else {
-                                self.date.month += 1;
-                            }
-                        } else {
-                            self.date.day += 1;
-                        }
-                    } else {
-                        self.time.hour += 1;
-                    }
-                } else {
-                    self.time.minute += 1;
-                }
+    /// Year since 2000, month, day, hour, minute and second, one byte each.
+    pub fn to_bytes(&self) -> [u8; 6] {
+        [
+            self.date.year.saturating_sub(2000) as u8,
+            self.date.month as u8,
+            self.date.day as u8,
+            self.time.hour as u8,
+            self.time.minute as u8,
+            self.time.second as u8,
+        ]
+    }
+
+    pub fn from_bytes(buf: &[u8; 6]) -> Self {
+        Date::from_ymd(buf[0] as u16 + 2000, buf[1] as u16, buf[2] as u16).with_hms(
+            buf[3] as u16,
+            buf[4] as u16,
+            buf[5] as u16,
+        )
+    }
+
+    /// Seconds since 2000-01-01 00:00:00.
+    pub fn timestamp(&self) -> u32 {
+        let mut days = 0;
+        for year in 2000..self.date.year {
+            days += if year % 4 == 0 { 366 } else { 365 };
+        }
+        for month in 1..self.date.month {
+            days += Date::get_days_in_month(month) as u32;
+        }
+        if self.date.month > 2 && self.date.year % 4 == 0 {
+            days += 1;
+        }
+        days += (self.date.day as u32).saturating_sub(1);
+        days * 86400
+            + self.time.hour as u32 * 3600
+            + self.time.minute as u32 * 60
+            + self.time.second as u32
+    }
+
+    /// The time `seconds` later, with the leap years `timestamp` knows.
+    pub fn add_seconds(mut self, seconds: u32) -> Self {
+        let seconds = self.time.hour as u32 * 3600
+            + self.time.minute as u32 * 60
+            + self.time.second as u32
+            + seconds;
+        let time = seconds % 86400;
+        self.time = Time::from_hms(
+            (time / 3600) as u16,
+            (time / 60 % 60) as u16,
+            (time % 60) as u16,
+        );
+        for _ in 0..seconds / 86400 {
+            let mut days = Date::get_days_in_month(self.date.month);
+            if self.date.month == 2 && self.date.year % 4 == 0 {
+                days += 1;
+            }
+            if self.date.day < days {
+                self.date.day += 1;
+            } else if self.date.month < 12 {
+                self.date.day = 1;
+                self.date.month += 1;
             } else {
-                self.time.second += 1;
+                self.date = Date::from_ymd(self.date.year + 1, 1, 1);
             }
-            dur.0 -= 1;
         }
         self
     }
@@ -205,16 +218,3 @@ impl Duration {
         self.0 / 3600
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_time_adding() {
-        assert_eq!(
-            Time::from_hms(1, 30, 30),
-            Time::from_hms(0, 30, 30).add_duration(Duration(3600))
-        );
-    }
-}